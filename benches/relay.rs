@@ -0,0 +1,88 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Loopback throughput of the spliced & buffered TCP relays
+//!
+//! Run with `cargo bench --features bench --bench relay`, `RELAY_BENCH_MIB` setting the MiB relayed per mode (64 by default)
+
+use std::{
+    env,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use krustacean::bench::relay;
+
+/// MiB relayed per mode unless `RELAY_BENCH_MIB` is set
+const DEFAULT_MIB: usize = 64usize;
+
+/// Size of the client writes & upstream reads
+const CHUNK: usize = 1usize << 20;
+
+/// Buffer size of the buffered relay, the default TCP buffer size
+const BUFFER_SIZE: usize = 4096usize;
+
+/// Client to upstream transfer of `total` bytes through the relay
+async fn run(spliced: bool, total: usize) -> Duration {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let (relay_client, _) = listener.accept().await.unwrap();
+    let relay_upstream = TcpStream::connect(addr).await.unwrap();
+    let (mut upstream, _) = listener.accept().await.unwrap();
+
+    let relay = tokio::spawn(async move { relay(&relay_client, &relay_upstream, spliced, BUFFER_SIZE).await });
+
+    let start = Instant::now();
+    let writer = tokio::spawn(async move {
+        let chunk = vec![0u8; CHUNK];
+        for _ in 0..total / CHUNK {
+            client.write_all(&chunk).await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+        client
+    });
+
+    let mut buf = vec![0u8; CHUNK];
+    let mut received = 0usize;
+    loop {
+        match upstream.read(&mut buf).await.unwrap() {
+            0usize => break,
+            n => received += n,
+        }
+    }
+
+    let elapsed = start.elapsed();
+    drop(upstream);
+    drop(writer.await.unwrap());
+    relay.await.unwrap().unwrap();
+
+    assert_eq!(total, received);
+    elapsed
+}
+
+#[tokio::main]
+async fn main() {
+    let mib = match env::var("RELAY_BENCH_MIB") {
+        Ok(v) => v
+            .parse::<usize>()
+            .expect("RELAY_BENCH_MIB must be a number of MiB"),
+        Err(_) => DEFAULT_MIB,
+    };
+
+    for spliced in [false, true] {
+        let elapsed = run(spliced, mib * CHUNK).await;
+
+        println!(
+            "{} relay: {mib} MiB in {elapsed:?} - {:.0} MiB/s",
+            if spliced { "spliced" } else { "buffered" },
+            mib as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,24 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Entry points of the benchmarks, built with the `bench` feature only
+
+use std::io::Result;
+use tokio::net::TcpStream;
+
+use crate::utils::structs::{Settings, fixtures::default_settings};
+
+use super::relay::{self, Relayed};
+
+/// Relays a TCP session like the TCP forwarder, returning the bytes sent & received
+///
+/// * Without splicing, data is copied through `buffer_size` bytes buffers
+pub async fn relay(client: &TcpStream, upstream: &TcpStream, spliced: bool, buffer_size: usize) -> Result<(u64, u64)> {
+    let settings = Settings {
+        buffer_size,
+        ..default_settings()
+    };
+
+    relay::relay(client, upstream, spliced, &settings)
+        .await
+        .map(|Relayed { sent, received, .. }| (sent, received))
+}
@@ -1,16 +1,31 @@
-use std::{net::Ipv4Addr, time::Duration};
+use std::time::Duration;
 
-/// Connection timeout for upstream
-pub(super) const CONN_TIMEOUT: Duration = Duration::from_secs(2u64);
-
-/// TCP and UDP data buffer size, 4KB
+/// Health check data buffer size, 4KB
 pub(super) const BUFFER_SIZE: usize = 4096;
 
-/// Wait time for forwarder tasks to finish
-pub(super) const DRAIN_DURATION: Duration = Duration::from_secs(5u64);
+/// Maximum bytes moved by a single splice(2) call, the default pipe capacity
+pub(super) const SPLICE_LEN: usize = 65536;
+
+/// Maximum datagrams received or sent by a single recvmmsg(2) or sendmmsg(2) call
+pub(super) const UDP_BATCH: usize = 32;
+
+/// Transparent UDP reply sockets kept open by the UDP forwarder workers, the least recently used being closed first
+pub(super) const REPLY_SOCKET_CACHE_SIZE: usize = 256;
+
+/// Lower bound of the re-resolution interval of an upstream hostname, also used after a failed resolution
+pub(super) const MIN_RESOLVE_INTERVAL: Duration = Duration::from_secs(5u64);
+
+/// Upper bound of the re-resolution interval of an upstream hostname
+pub(super) const MAX_RESOLVE_INTERVAL: Duration = Duration::from_secs(300u64);
+
+/// Wait for the upstream hostnames of a new configuration, the slower ones being left to the resolver task
+pub(super) const PENDING_RESOLVE_TIMEOUT: Duration = Duration::from_secs(2u64);
+
+/// Interval at which the health checker picks up upstreams added by hostname resolution
+pub(super) const PROBE_REFRESH_INTERVAL: Duration = Duration::from_secs(1u64);
 
-/// Proxy listen IP - `127.0.0.2`
-pub(crate) const LISTEN_IP: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+/// Wait for the PROXY protocol header opening a connection from a load balancer
+pub(super) const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5u64);
 
-/// TCP connection backlog and UDP semaphore size
-pub(super) const CONN_BACKLOG: u32 = 100;
+/// Interval at which the systemd status is refreshed with the connection limit counters
+pub(super) const STATUS_INTERVAL: Duration = Duration::from_secs(10u64);
@@ -2,30 +2,34 @@
 
 use arc_swap::ArcSwap;
 use log::{error, info, warn};
-use socket2::{Domain, Protocol, SockRef, Socket, Type};
-use std::{
-    io::Result,
-    net::{Ipv4Addr, SocketAddrV4},
-    sync::Arc,
-};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, UdpSocket},
-    select,
-    sync::{Semaphore, TryAcquireError, watch::Receiver},
-    task::JoinSet,
-    time::timeout,
-};
+use socket2::SockRef;
+use std::{io::Result, sync::Arc};
+use tokio::{io::AsyncWriteExt, select, sync::watch::Receiver, task::JoinSet, time::timeout};
 
-use crate::utils::structs::{Actions, ForwarderMap, RuntimeConfigs};
+use crate::utils::{
+    limits::OverloadAction,
+    structs::{Actions, ForwarderMap, RuntimeConfigs, bindings},
+};
 
 use super::{
-    constants::{BUFFER_SIZE, CONN_BACKLOG, CONN_TIMEOUT, DRAIN_DURATION},
-    helpers::{create_tcp_listener, create_udp_socket_fd, recvfrom_cmsg},
+    constants::UDP_BATCH,
+    helpers::{
+        connect_tcp_upstream, is_loop, original_dst, rebind_tcp_listeners, rebind_udp_socket_fds, recvmmsg_cmsg, reset_on_close, set_socket_options,
+        tcp_accept, udp_readable,
+    },
+    proxy_protocol::{self, Transport},
+    relay::{Relayed, relay},
+    udp_sessions::{ReplySockets, UdpSession, UdpSessions, udp_session},
 };
 
 /// UDP forwarder function
-pub(crate) async fn udp_forwarder(mut rx: Receiver<Actions>, current_config: Arc<ArcSwap<RuntimeConfigs>>) -> Result<()> {
+///
+/// Datagrams are received in batches with recvmmsg(2) and grouped into sessions keyed by client address & original destination.
+/// A session reuses one upstream socket, relays every reply back to the client and expires after being idle or left without reply.
+/// At most `backlog` sessions are open at once across all workers, which share the reply sockets too.
+pub(crate) async fn udp_forwarder(
+    mut rx: Receiver<Actions>, current_config: Arc<ArcSwap<RuntimeConfigs>>, reply_sockets: Arc<ReplySockets>,
+) -> Result<()> {
     info!("UDP forwarder starting...");
 
     let action = rx.borrow().clone();
@@ -45,14 +49,18 @@ pub(crate) async fn udp_forwarder(mut rx: Receiver<Actions>, current_config: Arc
         _ => { /* RELOAD or INIT has no effect now */ },
     };
 
-    let (mut udp_map, mut udp_fd) = {
+    let mut udp_fds = Vec::new();
+    let mut udp_listeners = {
         let config = current_config.load();
-        (config.udp_map.clone(), create_udp_socket_fd(config.port)?)
+        let (addrs, listeners) = bindings(&config.udp_listeners);
+        rebind_udp_socket_fds(&mut udp_fds, &addrs)?;
+        listeners
     };
-    let semaphore = Arc::new(Semaphore::new(CONN_BACKLOG as usize));
+    let sessions = Arc::new(UdpSessions::default());
     let mut tasks = JoinSet::new();
     let mut force_kill = false;
-    let mut buf = [0u8; BUFFER_SIZE];
+    let udp_buffers = |size: usize| (0..UDP_BATCH).map(|_| vec![0u8; size]).collect::<Vec<_>>();
+    let mut bufs = udp_buffers(current_config.load().udp_buffer_size);
 
     'udp_forwarder_loop: loop {
         select! {
@@ -61,20 +69,24 @@ pub(crate) async fn udp_forwarder(mut rx: Receiver<Actions>, current_config: Arc
                     Ok(_) => {
                         let action = rx.borrow().clone();
                         match action {
-                            Actions::RELOAD(port_changed) => {
+                            Actions::RELOAD(listen_changed) => {
                                 info!("RELOAD signal received by UDP forwarder...");
 
                                 let config = current_config.load();
-                                if port_changed {
-                                    match create_udp_socket_fd(config.port) {
-                                        Ok(f) => {
-                                            udp_fd = f;
-                                            udp_map = config.udp_map.clone();
+                                if bufs[0].len() != config.udp_buffer_size {
+                                    bufs = udp_buffers(config.udp_buffer_size);
+                                }
+
+                                let (addrs, listeners) = bindings(&config.udp_listeners);
+                                if listen_changed {
+                                    match rebind_udp_socket_fds(&mut udp_fds, &addrs) {
+                                        Ok(_) => {
+                                            udp_listeners = listeners;
                                         },
                                         Err(e) => error!("{e}")
                                     };
                                 } else {
-                                    udp_map = config.udp_map.clone();
+                                    udp_listeners = listeners;
                                 }
 
                                 continue 'udp_forwarder_loop;
@@ -107,7 +119,7 @@ pub(crate) async fn udp_forwarder(mut rx: Receiver<Actions>, current_config: Arc
                 };
             }
 
-            result = udp_fd.readable() => {
+            (i, result) = udp_readable(&udp_fds) => {
                 let mut guard = match result {
                     Ok(g) => g,
                     Err(e) => {
@@ -116,118 +128,63 @@ pub(crate) async fn udp_forwarder(mut rx: Receiver<Actions>, current_config: Arc
                     }
                 };
 
-                let recv_res = recvfrom_cmsg(&udp_fd, &mut buf);
-
-                guard.clear_ready();
+                let received = recvmmsg_cmsg(&udp_fds[i], &mut bufs);
 
-                if let Some((src, len, orig_dst)) = recv_res {
-                    match semaphore.clone().try_acquire_owned() {
-                        Ok(p) => {
-                            let packet = buf[..len].to_vec();
-                            let udp_map = udp_map.clone();
+                // a full batch may leave datagrams behind, readiness is kept to come back for them
+                if received.len() < bufs.len() {
+                    guard.clear_ready();
+                }
 
-                            tasks.spawn(async move {
-                                let _permit = p; // hold acquired permit
+                for (buf, datagram) in bufs.iter().zip(received) {
+                    let Some((src, len, orig_dst)) = datagram else {
+                        continue;
+                    };
 
-                                let orig_dst_addr = orig_dst.ip();
-                                let orig_dst_port = orig_dst.port();
-                                info!("UDP intercepted for {orig_dst_addr}:{orig_dst_port} from {src}");
+                    let key = (src, orig_dst);
 
-                                match udp_map.get(&orig_dst_port) {
-                                    Some(proxy) => {
-                                        match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0u16)).await {
-                                            Ok(upstream_socket) => {
-                                                if let Err(e) = upstream_socket.send_to(&packet, proxy).await {
-                                                    error!("Failed to send UDP datagram to upstream {}:{} - {e}", proxy.0, proxy.1);
-                                                    return;
-                                                }
+                    match sessions.get(&key) {
+                        Some(session) => {
+                            session.touch();
 
-                                                let mut reply_buf = [0u8; BUFFER_SIZE];
-
-                                                match timeout(CONN_TIMEOUT, upstream_socket.recv_from(&mut reply_buf)).await {
-                                                    Ok(Ok((reply_len, _))) => {
-                                                        match Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)) {
-                                                            Ok(reply_socket) => {
-                                                                if let Err(e) = reply_socket.set_reuse_address(true) {
-                                                                    error!("Failed to set reuse address on UDP reply socket - {e}");
-                                                                    return;
-                                                                }
-
-                                                                if let Err(e) = reply_socket.set_reuse_port(true){
-                                                                    error!("Failed to set reuse port on UDP reply socket - {e}");
-                                                                    return;
-                                                                }
-
-                                                                if let Err(e) = reply_socket.set_ip_transparent_v4(true) {
-                                                                    error!("Failed to set IP transparent on UDP reply socket - {e}");
-                                                                    return;
-                                                                }
-
-                                                                if let Err(e) = reply_socket.set_nonblocking(true) {
-                                                                    error!("Failed to set non-blocking on UDP reply socket - {e}");
-                                                                    return;
-                                                                }
-
-                                                                if let Err(e) = reply_socket.bind(&SocketAddrV4::new(*orig_dst_addr, orig_dst_port).into()) {
-                                                                    error!("Failed to bind UDP reply socket to original destination {}:{} - {e}", orig_dst_addr, orig_dst_port);
-                                                                    return;
-                                                                }
-
-                                                                match UdpSocket::from_std(reply_socket.into()) {
-                                                                    Ok(reply_udp) => {
-                                                                        match reply_udp.send_to(&reply_buf[..reply_len], src).await {
-                                                                            Ok(_) => {
-                                                                                info!("UDP reply forwarded back to client {}", src);
-                                                                            },
-                                                                            Err(e) => {
-                                                                                error!("Failed to forward UDP reply back to client {} - {e}", src);
-                                                                            }
-                                                                        };
-
-                                                                        return;
-                                                                    },
-                                                                    Err(e) => {
-                                                                        error!("Failed to create UDP socket from std for reply - {e}");
-                                                                        return;
-                                                                    }
-                                                                };
-                                                            },
-                                                            Err(e) => {
-                                                                error!("Failed to create UDP socket for reply - {e}");
-                                                                return;
-                                                            }
-                                                        };
-                                                    },
-                                                    Ok(Err(e)) => {
-                                                        error!("Failed to receive UDP datagram from upstream {}:{} - {e}", proxy.0, proxy.1);
-                                                        return;
-                                                    },
-                                                    Err(_) => {
-                                                        error!("Timed out while trying to receive UDP datagram from upstream {}:{}", proxy.0, proxy.1);
-                                                        return;
-                                                    }
-                                                };
-                                            },
-                                            Err(e) => {
-                                                error!("Failed to create and bind upstream UDP socket {e}");
-                                                return;
-                                            }
+                            match session.try_send(&buf[..len]) {
+                                Ok(_) => session.requested(),
+                                Err(e) => error!("Failed to send UDP datagram to upstream for session {src} -> {orig_dst} - {e}"),
+                            };
+                        },
+                        None => {
+                            let listener = &udp_listeners[i];
+                            info!("UDP intercepted by {} for {orig_dst} from {src}", listener.name);
+                            let sessions_limit = current_config.load().udp_limit.clone();
+
+                            match listener.map.lookup(&orig_dst).map(|r| (r.target.resolve(&src, &orig_dst), r.target, r.settings)) {
+                                Some((Some((proxy, _)), target, _)) if is_loop(&proxy, &orig_dst, target, &listener.addrs) => {
+                                    warn!("Refusing to forward UDP from {src} for {orig_dst} as upstream {proxy} loops back to the proxy");
+                                },
+                                Some((Some((proxy, lease)), _, settings)) => match sessions_limit.try_acquire() {
+                                    Ok(permit) => {
+                                        let proxy_header = settings.proxy_protocol.map(|v| proxy_protocol::header(v, Transport::Datagram, &src, &orig_dst));
+                                        let session = Arc::new(UdpSession::new(proxy, lease, listener.name.clone(), settings, proxy_header));
+
+                                        // queued until the session task has created the upstream socket
+                                        match session.try_send(&buf[..len]) {
+                                            Ok(_) => session.requested(),
+                                            Err(e) => error!("Failed to send UDP datagram to upstream {proxy} - {e}"),
                                         };
+
+                                        sessions.insert(key, session.clone());
+                                        tasks.spawn(udp_session(key, session, sessions.clone(), reply_sockets.clone(), rx.clone(), current_config.clone(), permit));
                                     },
-                                    None => {
-                                        warn!("No upstream mapping provided for destination UDP port {orig_dst_port}");
-                                        return;
+                                    Err(_) => {
+                                        warn!("UDP session table is full, dropping packets... ({sessions_limit})");
                                     }
-                                };
-                            });
-                        },
-                        Err(e) => match e {
-                            TryAcquireError::Closed => {
-                                error!("UDP forwarder backlog semaphore is closed");
-                            },
-                            TryAcquireError::NoPermits => {
-                                warn!("UDP forwarder is busy, dropping packets...");
-                            }
+                                },
+                                Some((None, _, _)) => {
+                                    warn!("No healthy upstream for UDP destination {orig_dst}");
+                                },
+                                None => {
+                                    warn!("No upstream mapping provided for UDP destination {orig_dst}");
+                                }
+                            };
                         }
                     };
                 }
@@ -246,7 +203,10 @@ pub(crate) async fn udp_forwarder(mut rx: Receiver<Actions>, current_config: Arc
 
     let drain = async { (!tasks.is_empty()).then(async || while tasks.join_next().await.is_some() {}) };
 
-    if timeout(DRAIN_DURATION, drain).await.is_err() {
+    if timeout(current_config.load().drain_timeout, drain)
+        .await
+        .is_err()
+    {
         warn!("Forced exit in UDP forwarder: tasks didn't complete in time");
     }
 
@@ -255,6 +215,11 @@ pub(crate) async fn udp_forwarder(mut rx: Receiver<Actions>, current_config: Arc
 }
 
 /// TCP forwarder function
+///
+/// Each intercepted connection is relayed with its upstream in both directions until both sides close, with splice(2) if `tcp_splice` is set.
+/// EOF on one side is propagated to the other as a half-close (FIN) while the opposite direction keeps flowing.
+/// Sessions idle for the idle timeout of their rule or older than its maximum lifetime are closed on both sides.
+/// Connections beyond the global limit are paused or reset as configured, those beyond their rule limit are reset.
 pub(crate) async fn tcp_forwarder(mut rx: Receiver<Actions>, current_config: Arc<ArcSwap<RuntimeConfigs>>) -> Result<()> {
     info!("TCP forwarder starting...");
 
@@ -275,34 +240,44 @@ pub(crate) async fn tcp_forwarder(mut rx: Receiver<Actions>, current_config: Arc
         _ => { /* RELOAD or INIT has no effect now */ },
     };
 
-    let (mut tcp_map, mut listener) = {
+    let mut listeners = Vec::new();
+    let mut tcp_listeners = {
         let config = current_config.load();
-        (config.tcp_map.clone(), create_tcp_listener(config.port)?)
+        let (addrs, tcp_listeners) = bindings(&config.tcp_listeners);
+        rebind_tcp_listeners(&mut listeners, &addrs, config.backlog)?;
+        tcp_listeners
     };
+    let mut backlog = current_config.load().backlog;
     let mut tasks = JoinSet::new();
     let mut force_kill = false;
 
     'tcp_forwarder_loop: loop {
+        let (limit, overload) = {
+            let config = current_config.load();
+            (config.tcp_limit.clone(), config.tcp_overload)
+        };
+
         select! {
             sig = rx.changed() => {
                 match sig {
                     Ok(_) => {
                         let action = rx.borrow().clone();
                         match action {
-                            Actions::RELOAD(port_changed) => {
+                            Actions::RELOAD(listen_changed) => {
                                 info!("RELOAD signal received by TCP forwarder...");
 
                                 let config = current_config.load();
-                                if port_changed {
-                                    match create_tcp_listener(config.port) {
-                                        Ok(l) => {
-                                            listener = l;
-                                            tcp_map = config.tcp_map.clone();
+                                let (addrs, new_tcp_listeners) = bindings(&config.tcp_listeners);
+                                if listen_changed || backlog != config.backlog {
+                                    backlog = config.backlog;
+                                    match rebind_tcp_listeners(&mut listeners, &addrs, backlog) {
+                                        Ok(_) => {
+                                            tcp_listeners = new_tcp_listeners;
                                         },
                                         Err(e) => error!("{e}")
                                     };
                                 } else {
-                                    tcp_map = config.tcp_map.clone();
+                                    tcp_listeners = new_tcp_listeners;
                                 }
 
                                 continue 'tcp_forwarder_loop;
@@ -335,72 +310,100 @@ pub(crate) async fn tcp_forwarder(mut rx: Receiver<Actions>, current_config: Arc
                 };
             }
 
-            result = listener.accept() => {
+            (permit, (i, result)) = async {
+                // pausing leaves new connections in the kernel backlog until a session ends
+                let permit = match (&limit, overload) {
+                    (Some(l), OverloadAction::Pause) => Some(l.acquire().await),
+                    _ => None,
+                };
+
+                (permit, tcp_accept(&listeners).await)
+            } => {
                 match result {
                     Ok((mut client, src)) => {
-                        let tcp_map = tcp_map.clone();
+                        let permit = match (permit, &limit) {
+                            (None, Some(l)) => match l.try_acquire() {
+                                Ok(p) => Some(p),
+                                Err(rejected) => {
+                                    warn!("TCP connection from {src} reset as {} connections are already open ({rejected} rejected so far)", l.max());
+                                    reset_on_close(&client);
+                                    continue 'tcp_forwarder_loop;
+                                }
+                            },
+                            (permit, _) => permit,
+                        };
+
+                        let listener = tcp_listeners[i].clone();
+                        let spliced = current_config.load().tcp_splice;
 
                         tasks.spawn(async move {
-                            let orig_dst = SockRef::from(&client).original_dst_v4().map(|o| o.as_socket_ipv4());
-
-                            match orig_dst {
-                                Ok(Some(orig)) => {
-                                    let orig_dst_addr = orig.ip();
-                                    let orig_dst_port = orig.port();
-                                    info!("TCP intercepted for {}:{} from {}", orig_dst_addr, orig_dst_port, src);
-
-                                    match tcp_map.get(&orig_dst_port) {
-                                        Some(proxy) => {
-                                            match timeout(CONN_TIMEOUT, TcpStream::connect(proxy)).await {
-                                                Ok(Ok(mut upstream_conn)) => {
-                                                    let mut buf = [0u8; BUFFER_SIZE];
-
-                                                    match client.read(&mut buf).await {
-                                                        Ok(len) => {
-                                                            if let Err(e) = upstream_conn.write_all(&buf[..len]).await {
-                                                                error!("Failed to forward TCP to upstream {}:{} - {e}", proxy.0, proxy.1);
-                                                                return;
-                                                            };
-
-                                                            match timeout(CONN_TIMEOUT, upstream_conn.read(&mut buf)).await {
-                                                                Ok(Ok(reply_len)) => {
-                                                                    match client.write_all(&buf[..reply_len]).await {
-                                                                        Ok(_) => {
-                                                                            info!("TCP reply forwarded back to client {}", src);
-                                                                        },
-                                                                        Err(e) => {
-                                                                            error!("Failed to forward TCP reply back to client {} - {e}", src);
-                                                                        }
-                                                                    };
-                                                                },
-                                                                Ok(Err(e)) => {
-                                                                    error!("Failed to read TCP reply from upstream {}:{} - {e}", proxy.0, proxy.1);
-                                                                },
-                                                                Err(_) => {
-                                                                    error!("Timed out while trying to read TCP reply from upstream {}:{}", proxy.0, proxy.1);
-                                                                }
-                                                            };
+                            let _permit = permit;
+
+                            // behind a load balancer, the client & original destination come from the PROXY protocol header
+                            let endpoints = match &listener.proxy_from {
+                                Some(trusted) => proxy_protocol::accept(&mut client, trusted).await,
+                                None => original_dst(&client).map(|orig| (src, orig)),
+                            };
+
+                            match endpoints {
+                                Ok((src, orig)) => {
+                                    info!("TCP intercepted by {} for {} from {}", listener.name, orig, src);
+
+                                    match listener.map.lookup(&orig) {
+                                        Some(route) => {
+                                            let _rule_permit = match route.limit.map(|l| l.try_acquire()).transpose() {
+                                                Ok(p) => p,
+                                                Err(rejected) => {
+                                                    warn!("TCP connection from {} reset by the connection limit of its rule for {} ({rejected} rejected so far)", src, orig);
+                                                    reset_on_close(&client);
+                                                    return;
+                                                }
+                                            };
+
+                                            if let Err(e) = set_socket_options(SockRef::from(&client), &route.settings.socket_options) {
+                                                warn!("Failed to set socket options on TCP connection from {} - {e}", src);
+                                            }
+
+                                            match connect_tcp_upstream(route.target, &src, &orig, &route.settings, &listener.addrs).await {
+                                                Ok((mut upstream_conn, proxy, _lease)) => {
+                                                    if let Some(version) = route.settings.proxy_protocol {
+                                                        let header = proxy_protocol::header(version, Transport::Stream, &src, &orig);
+                                                        if let Err(e) = upstream_conn.write_all(&header).await {
+                                                            error!("Failed to send PROXY protocol header to upstream {} - {e}", proxy);
+                                                            reset_on_close(&client);
+                                                            return;
+                                                        }
+                                                    }
+
+                                                    match relay(&client, &upstream_conn, spliced, &route.settings).await {
+                                                        Ok(Relayed { sent, received, expired: None }) => {
+                                                            info!("TCP session {} <-> {} closed - {sent} bytes sent, {received} bytes received", src, proxy);
+                                                        },
+                                                        Ok(Relayed { sent, received, expired: Some(expiry) }) => {
+                                                            info!("TCP session {} <-> {} closed {expiry} - {sent} bytes sent, {received} bytes received", src, proxy);
                                                         },
                                                         Err(e) => {
-                                                            error!("Failed to read from TCP client {} - {e}", src);
+                                                            error!("TCP session {} <-> {} failed - {e}", src, proxy);
                                                         }
                                                     };
                                                 },
-                                                Ok(Err(e)) => {
-                                                    error!("Failed to connect to upstream {}:{} - {e}", proxy.0, proxy.1);
-                                                },
-                                                Err(_) => {
-                                                    error!("Timed out while trying to connect to upstream {}:{}", proxy.0, proxy.1);
+                                                Err(e) => {
+                                                    error!("Failed to connect to an upstream for TCP destination {} - {e}", orig);
+                                                    reset_on_close(&client);
                                                 }
                                             };
                                         },
                                         None => {
-                                            warn!("No upstream mapping found for destination TCP port {}", orig_dst_port);
+                                            warn!("No upstream mapping found for TCP destination {}", orig);
                                         }
                                     };
                                 },
-                                _ => {
-                                    error!("Failed to get original destination for TCP connection from {}", src);
+                                Err(e) if listener.proxy_from.is_some() => {
+                                    warn!("TCP connection from {} rejected - {e}", src);
+                                    reset_on_close(&client);
+                                },
+                                Err(e) => {
+                                    error!("Failed to get original destination for TCP connection from {} - {e}", src);
                                 }
                             };
                         });
@@ -424,10 +427,106 @@ pub(crate) async fn tcp_forwarder(mut rx: Receiver<Actions>, current_config: Arc
 
     let drain = async { (!tasks.is_empty()).then(async || while tasks.join_next().await.is_some() {}) };
 
-    if timeout(DRAIN_DURATION, drain).await.is_err() {
+    if timeout(current_config.load().drain_timeout, drain)
+        .await
+        .is_err()
+    {
         warn!("Forced exit in TCP forwarder: tasks didn't complete in time");
     }
 
     info!("TCP forwarder shut down");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+        sync::watch,
+        time::sleep,
+    };
+
+    use crate::utils::structs::fixtures::runtime_configs;
+
+    use super::*;
+
+    /// Connects to the forwarder, retrying until it listens
+    async fn connect(addr: SocketAddr) -> TcpStream {
+        for _ in 0..100 {
+            if let Ok(stream) = TcpStream::connect(addr).await {
+                return stream;
+            }
+            sleep(Duration::from_millis(10u64)).await;
+        }
+
+        panic!("TCP forwarder not listening at {addr}");
+    }
+
+    #[tokio::test]
+    async fn test_tcp_forwarder_half_close() {
+        for spliced in [true, false] {
+            let upstream_listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+                .await
+                .unwrap();
+            let listen = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+                .unwrap()
+                .local_addr()
+                .unwrap();
+
+            // the PROXY protocol header gives the original destination without TPROXY
+            let config = runtime_configs(&format!(
+                r#"{{
+                    "listen": ["127.0.0.1"],
+                    "tcp_port": {},
+                    "accept_proxy": true,
+                    "proxy_from": ["127.0.0.1"],
+                    "tcp_splice": {spliced},
+                    "tcp": [{{ "upstream": "127.0.0.1", "upstream_port": {}, "orig_port": 80 }}]
+                }}"#,
+                listen.port(),
+                upstream_listener.local_addr().unwrap().port()
+            ));
+            let (tx, rx) = watch::channel(Actions::INIT);
+            let forwarder = tokio::spawn(tcp_forwarder(rx, Arc::new(ArcSwap::from_pointee(config))));
+
+            let mut client = connect(listen).await;
+            client
+                .write_all(b"PROXY TCP4 192.0.2.1 10.0.0.1 40000 80\r\nrequest")
+                .await
+                .unwrap();
+            client.shutdown().await.unwrap();
+
+            // the upstream reads the request then EOF
+            let (mut upstream, _) = upstream_listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            upstream.read_to_end(&mut request).await.unwrap();
+            assert_eq!(b"request".to_vec(), request);
+
+            // replies still reach the client
+            let mut buf = [0u8; 5usize];
+            for reply in [b"reply", b"again"] {
+                upstream.write_all(reply).await.unwrap();
+                client.read_exact(&mut buf).await.unwrap();
+                assert_eq!(reply, &buf);
+            }
+
+            // it closes once the upstream is done too
+            upstream.shutdown().await.unwrap();
+            assert_eq!(0usize, client.read(&mut buf).await.unwrap());
+
+            tx.send_replace(Actions::SHUTDOWN);
+            timeout(Duration::from_secs(1u64), forwarder)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,383 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use arc_swap::ArcSwap;
+use log::{error, info, warn};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{ErrorKind, Result},
+    mem,
+    net::SocketAddr,
+    sync::Arc,
+};
+use tokio::{
+    select,
+    sync::watch::Receiver,
+    task::JoinSet,
+    time::{Instant, sleep_until, timeout},
+};
+
+use crate::utils::{
+    structs::{Actions, ForwarderMap, RuntimeConfigs, SocketOptions, Target},
+    upstreams::{HealthCheck, Upstream, UpstreamPool},
+};
+
+use super::{
+    constants::{BUFFER_SIZE, PROBE_REFRESH_INTERVAL},
+    helpers::{Egress, connect_tcp, create_udp_upstream_socket},
+};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Probe {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Probe::Tcp => write!(f, "TCP"),
+            Probe::Udp => write!(f, "UDP"),
+        }
+    }
+}
+
+/// Upstream address checked with a given health check & egress, shared by every pool member having all three
+type ProbeKey = (Probe, SocketAddr, HealthCheck, Egress);
+
+/// Health of a probed upstream
+struct ProbeState {
+    up: bool,
+    /// Consecutive results disagreeing with `up`
+    streak: u32,
+    due: Instant,
+    running: bool,
+    members: Vec<Arc<Upstream>>,
+}
+
+impl ProbeState {
+    /// Records a probe result, returning the new health if it changed
+    fn record(&mut self, ok: bool, check: &HealthCheck) -> Option<bool> {
+        if ok == self.up {
+            self.streak = 0u32;
+            return None;
+        }
+
+        self.streak += 1u32;
+        if self.streak < if self.up { check.fall } else { check.rise } {
+            return None;
+        }
+
+        self.up = ok;
+        self.streak = 0u32;
+        self.members.iter().for_each(|m| m.set_up(ok));
+        Some(ok)
+    }
+}
+
+/// Every health checked pool member of the configuration, grouped by probe
+///
+/// Probes leave like the traffic of their rule, with its mark, bind address & interface
+fn probed_members(config: &RuntimeConfigs) -> HashMap<ProbeKey, Vec<Arc<Upstream>>> {
+    fn pools<M: ForwarderMap>(probe: Probe, map: &M) -> impl Iterator<Item = (Probe, &Arc<UpstreamPool>, Egress)> {
+        map.rules().iter().filter_map(move |r| match &r.target {
+            Target::Upstream(pool) => Some((probe, pool, Egress::probe(&r.settings))),
+            Target::Passthrough => None,
+        })
+    }
+
+    let tcp_pools = config
+        .tcp_listeners
+        .iter()
+        .flat_map(|l| pools(Probe::Tcp, &l.map));
+    let udp_pools = config
+        .udp_listeners
+        .iter()
+        .flat_map(|l| pools(Probe::Udp, &l.map));
+
+    let mut members: HashMap<ProbeKey, Vec<Arc<Upstream>>> = HashMap::new();
+    for (probe, pool, egress) in tcp_pools.chain(udp_pools) {
+        if let Some(check) = pool.health_check() {
+            for upstream in pool.upstreams().iter() {
+                members
+                    .entry((probe, upstream.addr, check.clone(), egress))
+                    .or_default()
+                    .push(upstream.clone());
+            }
+        }
+    }
+
+    members
+}
+
+/// Replaces the probes with those of the configuration, keeping the health of the upstreams already probed
+fn refresh(probes: &mut HashMap<ProbeKey, ProbeState>, config: &RuntimeConfigs) {
+    let now = Instant::now();
+    let mut old = mem::take(probes);
+
+    for (key, members) in probed_members(config) {
+        let state = match old.remove(&key) {
+            Some(s) => {
+                members.iter().for_each(|m| m.set_up(s.up));
+                ProbeState { members, ..s }
+            },
+            None => ProbeState {
+                up: true,
+                streak: 0u32,
+                due: now,
+                running: false,
+                members,
+            },
+        };
+
+        probes.insert(key, state);
+    }
+}
+
+/// Checks a TCP upstream by connecting to it
+async fn probe_tcp(addr: SocketAddr, check: &HealthCheck, egress: &Egress) -> bool {
+    matches!(
+        timeout(check.timeout, connect_tcp(addr, &SocketOptions::default(), egress)).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Checks a UDP upstream by sending it the probe payload
+///
+/// * With `expect`, a reply containing it must arrive in time
+/// * Otherwise, only an ICMP error (connection refused) fails the check
+async fn probe_udp(addr: SocketAddr, check: &HealthCheck, egress: &Egress) -> bool {
+    let socket = match create_udp_upstream_socket(&addr, egress).await {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+
+    if socket.send(&check.payload).await.is_err() {
+        return false;
+    }
+
+    let mut buf = [0u8; BUFFER_SIZE];
+    match (timeout(check.timeout, socket.recv(&mut buf)).await, &check.expect) {
+        (Ok(Ok(len)), Some(expect)) => buf[..len]
+            .windows(expect.len())
+            .any(|w| w == expect.as_slice()),
+        (Ok(Ok(_)), None) => true,
+        (Ok(Err(e)), _) => e.kind() != ErrorKind::ConnectionRefused && check.expect.is_none(),
+        (Err(_), expect) => expect.is_none(),
+    }
+}
+
+/// Probes an upstream through the egress of its rule
+async fn probe(key: ProbeKey) -> (ProbeKey, bool) {
+    let ok = match key.0 {
+        Probe::Tcp => probe_tcp(key.1, &key.2, &key.3).await,
+        Probe::Udp => probe_udp(key.1, &key.2, &key.3).await,
+    };
+
+    (key, ok)
+}
+
+/// Health checker function
+///
+/// Periodically probes every upstream of the pools having a health check.
+/// Members are marked down or up only after `fall` or `rise` consecutive results, so forwarders skip dead targets.
+pub(crate) async fn health_checker(mut rx: Receiver<Actions>, current_config: Arc<ArcSwap<RuntimeConfigs>>) -> Result<()> {
+    info!("Health checker starting...");
+
+    let action = rx.borrow().clone();
+    match action {
+        Actions::STOP(s) => {
+            info!("Health checker shut down before starting as {s} failed");
+            return Ok(());
+        },
+        Actions::PANICKED => {
+            info!("Health checker shut down before starting as someone panicked");
+            return Ok(());
+        },
+        Actions::KILL | Actions::SHUTDOWN => {
+            info!("Health checker shut down before starting");
+            return Ok(());
+        },
+        _ => { /* RELOAD or INIT has no effect now */ },
+    };
+
+    let mut probes = HashMap::new();
+    refresh(&mut probes, &current_config.load());
+
+    let mut tasks = JoinSet::new();
+
+    'health_checker_loop: loop {
+        let next_due = probes
+            .values()
+            .filter(|p| !p.running)
+            .map(|p| p.due)
+            .fold(Instant::now() + PROBE_REFRESH_INTERVAL, Instant::min);
+
+        select! {
+            sig = rx.changed() => {
+                match sig {
+                    Ok(_) => {
+                        let action = rx.borrow().clone();
+                        match action {
+                            Actions::RELOAD(_) => {
+                                info!("RELOAD signal received by Health checker...");
+                                refresh(&mut probes, &current_config.load());
+                                continue 'health_checker_loop;
+                            },
+                            Actions::STOP(s) => {
+                                info!("{s} failed...Shutting down Health checker...");
+                                break 'health_checker_loop;
+                            },
+                            Actions::KILL | Actions::PANICKED | Actions::SHUTDOWN => {
+                                info!("Shutting down Health checker...");
+                                break 'health_checker_loop;
+                            },
+                            Actions::INIT => {/* INIT will not come here */}
+                        }
+                    },
+                    Err(_) => {
+                        error!("Signal channel closed...Shutting down Health checker...");
+                        break 'health_checker_loop;
+                    }
+                };
+            }
+
+            _ = sleep_until(next_due) => {
+                // pools of hostname upstreams change with their resolution
+                let config = current_config.load();
+                refresh(&mut probes, &config);
+                let now = Instant::now();
+
+                for (key, state) in probes.iter_mut().filter(|(_, p)| !p.running && p.due <= now) {
+                    state.running = true;
+                    state.due = now + key.2.interval;
+                    tasks.spawn(probe(key.clone()));
+                }
+            }
+
+            Some(res) = tasks.join_next(), if !tasks.is_empty() => {
+                let (key, ok) = match res {
+                    Ok(r) => r,
+                    Err(e) => {
+                        error!("Health check task join error: {e}");
+                        continue 'health_checker_loop;
+                    }
+                };
+
+                // probes removed by a reload meanwhile are ignored
+                if let Some(state) = probes.get_mut(&key) {
+                    state.running = false;
+
+                    match state.record(ok, &key.2) {
+                        Some(true) => info!("{} upstream {} is up after {} successful health checks", key.0, key.1, key.2.rise),
+                        Some(false) => warn!("{} upstream {} is down after {} failed health checks", key.0, key.1, key.2.fall),
+                        None => {},
+                    };
+                }
+            }
+        }
+    }
+
+    tasks.abort_all();
+
+    info!("Health checker shut down");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use crate::utils::upstreams::{Host, Member, Strategy};
+
+    use super::*;
+
+    fn check(expect: Option<&[u8]>) -> HealthCheck {
+        HealthCheck {
+            interval: Duration::from_secs(1u64),
+            timeout: Duration::from_millis(200u64),
+            rise: 2u32,
+            fall: 3u32,
+            payload: b"ping".to_vec(),
+            expect: expect.map(|e| e.to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_ProbeState_record() {
+        let check = check(None);
+        let pool = UpstreamPool::new(
+            Strategy::RoundRobin,
+            &[Member {
+                host: Host::Ip(IpAddr::from([10u8, 0u8, 0u8, 1u8])),
+                port: 53u16,
+                weight: 1u32,
+            }],
+            None,
+            None,
+        )
+        .unwrap();
+        let mut state = ProbeState {
+            up: true,
+            streak: 0u32,
+            due: Instant::now(),
+            running: false,
+            members: pool.upstreams().to_vec(),
+        };
+
+        assert_eq!(None, state.record(false, &check));
+        assert_eq!(None, state.record(false, &check));
+        assert_eq!(None, state.record(true, &check));
+        assert_eq!(None, state.record(false, &check));
+        assert_eq!(None, state.record(false, &check));
+        assert!(pool.upstreams()[0].is_up());
+        assert_eq!(Some(false), state.record(false, &check));
+        assert!(!pool.upstreams()[0].is_up());
+
+        assert_eq!(None, state.record(true, &check));
+        assert_eq!(Some(true), state.record(true, &check));
+        assert!(pool.upstreams()[0].is_up());
+    }
+
+    #[tokio::test]
+    async fn test_probe_tcp() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(probe_tcp(addr, &check(None), &Egress::default()).await);
+
+        drop(listener);
+        assert!(!probe_tcp(addr, &check(None), &Egress::default()).await);
+    }
+
+    #[tokio::test]
+    async fn test_probe_udp() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((len, src)) = server.recv_from(&mut buf).await {
+                let mut reply = b"pong:".to_vec();
+                reply.extend_from_slice(&buf[..len]);
+                let _ = server.send_to(&reply, src).await;
+            }
+        });
+
+        assert!(probe_udp(addr, &check(None), &Egress::default()).await);
+        assert!(probe_udp(addr, &check(Some(b"pong:ping")), &Egress::default()).await);
+        assert!(!probe_udp(addr, &check(Some(b"pang")), &Egress::default()).await);
+
+        let closed = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        assert!(!probe_udp(closed_addr, &check(None), &Egress::default()).await);
+    }
+}
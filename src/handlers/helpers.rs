@@ -1,92 +1,563 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use libc::{IP_RECVORIGDSTADDR, IPPROTO_IP, c_int, c_void, setsockopt, sockaddr_in, socklen_t};
-use log::error;
+use libc::{IP_RECVORIGDSTADDR, IPPROTO_IP, IPPROTO_IPV6, IPV6_RECVORIGDSTADDR, c_int, c_void, setsockopt, sockaddr_in6, socklen_t};
+use log::{error, warn};
 use nix::{
     cmsg_space,
     errno::Errno,
-    sys::socket::{ControlMessageOwned, MsgFlags, SockaddrIn, recvmsg},
+    sys::socket::{ControlMessageOwned, MsgFlags, MultiHeaders, RecvMsg, SockaddrIn, SockaddrIn6, SockaddrStorage, recv, recvmmsg, sendmmsg},
 };
-use socket2::{Domain, Protocol, Socket, Type};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
 use std::{
-    io::{Error, IoSliceMut, Result},
-    mem::size_of,
-    net::SocketAddrV4,
+    future::poll_fn,
+    io::{Error, ErrorKind, IoSlice, IoSliceMut, Result},
+    mem::{self, size_of},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     os::fd::AsRawFd,
+    sync::atomic::{AtomicU64, Ordering},
+    task::Poll,
+    time::Duration,
 };
-use tokio::{io::unix::AsyncFd, net::TcpListener};
-
-use super::constants::{CONN_BACKLOG, LISTEN_IP};
-
-pub(super) fn recvfrom_cmsg(sock: &AsyncFd<Socket>, buf: &mut [u8]) -> Option<(SocketAddrV4, usize, SocketAddrV4)> {
-    let mut cmsg_buf = cmsg_space!(sockaddr_in);
-    let mut iov = [IoSliceMut::new(buf)];
-
-    match recvmsg::<SockaddrIn>(sock.as_raw_fd(), &mut iov, Some(&mut cmsg_buf), MsgFlags::MSG_DONTWAIT) {
-        Ok(msg) => {
-            let src = match msg.address {
-                Some(a) => Some(SocketAddrV4::from(a)),
-                None => {
-                    error!("recvmsg(): missing source address...dropping packet...");
-                    None
-                },
-            };
-
-            let orig_dst = match msg.cmsgs() {
-                Ok(mut cmsgs) => match cmsgs.find_map(|cmsg| match cmsg {
-                    ControlMessageOwned::Ipv4OrigDstAddr(addr) => Some(SocketAddrV4::from(SockaddrIn::from(addr))),
-                    _ => None,
-                }) {
-                    Some(orig) => Some(orig),
-                    None => {
-                        error!("Couldn't find original destination");
-                        None
-                    },
-                },
-                Err(e) => {
-                    error!("Allocated space for CMSGs too small...errno: {e}");
-                    None
-                },
-            };
-
-            if let (Some(src), Some(orig_dst)) = (src, orig_dst) {
-                let len = msg.bytes;
-
-                Some((src, len, orig_dst))
-            } else {
+use tokio::{
+    io::{
+        Interest,
+        unix::{AsyncFd, AsyncFdReadyGuard},
+    },
+    net::{TcpListener, TcpSocket, TcpStream, UdpSocket},
+    time::{Instant, sleep, timeout},
+};
+
+use crate::utils::{
+    structs::{Interface, Settings, SocketOptions, Target},
+    upstreams::Lease,
+};
+
+/// UDP datagrams dropped for exceeding the receive buffer, on both client & upstream legs
+pub(super) static TRUNCATED_DATAGRAMS: AtomicU64 = AtomicU64::new(0u64);
+
+/// Datagram received by [`recvmmsg_cmsg`] - client address, length & original destination
+pub(super) type Datagram = (SocketAddr, usize, SocketAddr);
+
+/// Client address & original destination of a received datagram
+fn msg_addrs(msg: &RecvMsg<'_, '_, SockaddrStorage>) -> Option<(SocketAddr, SocketAddr)> {
+    let src = match msg.address {
+        Some(a) => match (a.as_sockaddr_in(), a.as_sockaddr_in6()) {
+            (Some(v4), _) => Some(SocketAddr::from(*v4)),
+            (_, Some(v6)) => Some(SocketAddr::from(*v6)),
+            _ => {
+                error!("recvmmsg(): unsupported source address family...dropping packet...");
                 None
-            }
+            },
+        },
+        None => {
+            error!("recvmmsg(): missing source address...dropping packet...");
+            None
+        },
+    };
+
+    let orig_dst = match msg.cmsgs() {
+        Ok(mut cmsgs) => match cmsgs.find_map(|cmsg| match cmsg {
+            ControlMessageOwned::Ipv4OrigDstAddr(addr) => Some(SocketAddr::from(SockaddrIn::from(addr))),
+            ControlMessageOwned::Ipv6OrigDstAddr(addr) => Some(SocketAddr::from(SockaddrIn6::from(addr))),
+            _ => None,
+        }) {
+            Some(orig) => Some(orig),
+            None => {
+                error!("Couldn't find original destination");
+                None
+            },
+        },
+        Err(e) => {
+            error!("Allocated space for CMSGs too small...errno: {e}");
+            None
         },
+    };
+
+    src.zip(orig_dst)
+}
+
+/// Counts & logs a datagram dropped for not fitting in the receive buffer
+fn record_truncated(leg: &str, from: SocketAddr, len: usize, size: usize) {
+    let count = TRUNCATED_DATAGRAMS.fetch_add(1u64, Ordering::Relaxed) + 1u64;
+    warn!("Dropped {len} bytes UDP datagram from {leg} {from} exceeding the {size} bytes buffer ({count} truncated so far)");
+}
+
+/// Receives up to one datagram per buffer with a single recvmmsg(2), along with their original destinations
+///
+/// * Entries match the buffers in order, datagrams missing their source or original destination being `None`
+/// * Datagrams cut by their buffer (MSG_TRUNC) are counted, logged & `None`
+/// * Fewer entries than buffers means the socket has been drained
+pub(super) fn recvmmsg_cmsg<B: AsMut<[u8]>>(sock: &AsyncFd<Socket>, bufs: &mut [B]) -> Vec<Option<Datagram>> {
+    let sizes = bufs
+        .iter_mut()
+        .map(|b| b.as_mut().len())
+        .collect::<Vec<_>>();
+    let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(bufs.len(), Some(cmsg_space!(sockaddr_in6)));
+    let mut iovs = bufs
+        .iter_mut()
+        .map(|b| [IoSliceMut::new(b.as_mut())])
+        .collect::<Vec<_>>();
+
+    // MSG_TRUNC makes the full length of cut datagrams be reported
+    match recvmmsg(
+        sock.as_raw_fd(),
+        &mut headers,
+        iovs.iter_mut(),
+        MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_TRUNC,
+        None,
+    ) {
+        Ok(msgs) => msgs
+            .zip(sizes)
+            .map(|(msg, size)| {
+                let (src, orig_dst) = msg_addrs(&msg)?;
+
+                match msg.flags.contains(MsgFlags::MSG_TRUNC) {
+                    true => {
+                        record_truncated("client", src, msg.bytes, size);
+                        None
+                    },
+                    false => Some((src, msg.bytes, orig_dst)),
+                }
+            })
+            .collect(),
         Err(e) => {
             if e != Errno::EWOULDBLOCK {
-                error!("recvmsg(): failed...errno: {e}");
+                error!("recvmmsg(): failed...errno: {e}");
             }
 
-            None
+            Vec::new()
+        },
+    }
+}
+
+/// Receives a datagram from a connected UDP socket without waiting, dropping it if cut by the buffer
+///
+/// * Returns [`ErrorKind::InvalidData`] for a truncated datagram, which is counted & logged
+pub(super) fn try_recv_whole(sock: &UdpSocket, buf: &mut [u8]) -> Result<usize> {
+    // MSG_TRUNC makes the full length of a cut datagram be returned
+    let len = sock.try_io(Interest::READABLE, || {
+        Ok(recv(sock.as_raw_fd(), buf, MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_TRUNC)?)
+    })?;
+
+    match len > buf.len() {
+        true => {
+            record_truncated("upstream", sock.peer_addr()?, len, buf.len());
+            Err(Error::new(ErrorKind::InvalidData, "Truncated datagram"))
         },
+        false => Ok(len),
+    }
+}
+
+/// Receives a datagram from a connected UDP socket like [`try_recv_whole`], waiting for one to arrive
+pub(super) async fn recv_whole(sock: &UdpSocket, buf: &mut [u8]) -> Result<usize> {
+    loop {
+        sock.readable().await?;
+
+        match try_recv_whole(sock, buf) {
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::InvalidData) => continue,
+            res => return res,
+        }
     }
 }
 
-pub(super) fn create_udp_socket_fd(port: u16) -> Result<AsyncFd<Socket>> {
-    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-    socket.set_ip_transparent_v4(true)?;
-    socket.set_recv_orig_dst_addr(true)?;
+/// Sends datagrams to `dst` with as few sendmmsg(2) calls as possible
+pub(super) async fn sendmmsg_to(sock: &UdpSocket, dst: SocketAddr, datagrams: &[&[u8]]) -> Result<()> {
+    let addr = SockaddrStorage::from(dst);
+    let mut sent = 0usize;
+
+    while sent < datagrams.len() {
+        let pending = &datagrams[sent..];
+        let iovs = pending
+            .iter()
+            .map(|d| [IoSlice::new(d)])
+            .collect::<Vec<_>>();
+        let addrs = vec![Some(addr); pending.len()];
+
+        sock.writable().await?;
+        match sock.try_io(Interest::WRITABLE, || {
+            // headers hold raw pointers, so they must not live across an await
+            let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(pending.len(), None);
+            Ok(sendmmsg(sock.as_raw_fd(), &mut headers, &iovs, &addrs, [], MsgFlags::MSG_DONTWAIT)?.count())
+        }) {
+            Ok(n) => sent += n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        };
+    }
+
+    Ok(())
+}
+
+/// Waits until any of the UDP sockets becomes readable, returning its index and readiness guard
+pub(super) async fn udp_readable(fds: &[AsyncFd<Socket>]) -> (usize, Result<AsyncFdReadyGuard<'_, Socket>>) {
+    poll_fn(|cx| {
+        for (i, fd) in fds.iter().enumerate() {
+            if let Poll::Ready(res) = fd.poll_read_ready(cx) {
+                return Poll::Ready((i, res));
+            }
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+/// Accepts a connection from any of the TCP listeners, returning the listener index along with the connection
+pub(super) async fn tcp_accept(listeners: &[TcpListener]) -> (usize, Result<(TcpStream, SocketAddr)>) {
+    poll_fn(|cx| {
+        for (i, listener) in listeners.iter().enumerate() {
+            if let Poll::Ready(res) = listener.poll_accept(cx) {
+                return Poll::Ready((i, res));
+            }
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+/// Original destination of an intercepted TCP connection, in the address family of the connection
+pub(super) fn original_dst(stream: &TcpStream) -> Result<SocketAddr> {
+    let sock = SockRef::from(stream);
+
+    let orig_dst = match stream.local_addr()? {
+        SocketAddr::V4(_) => sock.original_dst_v4()?,
+        SocketAddr::V6(_) => sock.original_dst_v6()?,
+    };
+
+    orig_dst
+        .as_socket()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Original destination is not an IP address"))
+}
+
+/// Creates a non-blocking socket of the address family of `addr` with IP transparency enabled
+fn create_transparent_socket(addr: &SocketAddr, ty: Type, protocol: Protocol) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(*addr), ty, Some(protocol))?;
+
+    match addr {
+        SocketAddr::V4(_) => socket.set_ip_transparent_v4(true)?,
+        SocketAddr::V6(_) => {
+            socket.set_only_v6(true)?;
+            socket.set_ip_transparent_v6(true)?;
+        },
+    };
+
     socket.set_nonblocking(true)?;
-    socket.bind(&SocketAddrV4::new(LISTEN_IP, port).into())?;
+    Ok(socket)
+}
+
+/// Creates a transparent UDP socket, every worker binding its own to the same address with SO_REUSEPORT
+fn create_udp_socket_fd(addr: SocketAddr) -> Result<AsyncFd<Socket>> {
+    let socket = create_transparent_socket(&addr, Type::DGRAM, Protocol::UDP)?;
+    socket.set_reuse_port(true)?;
+
+    match addr {
+        SocketAddr::V4(_) => socket.set_recv_orig_dst_addr(true)?,
+        SocketAddr::V6(_) => socket.set_recv_orig_dst_addr_v6(true)?,
+    };
+
+    socket.bind(&addr.into())?;
     AsyncFd::new(socket)
 }
 
-pub(super) fn create_tcp_listener(port: u16) -> Result<TcpListener> {
-    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
-    socket.set_ip_transparent_v4(true)?;
-    socket.set_nonblocking(true)?;
-    socket.bind(&SocketAddrV4::new(LISTEN_IP, port).into())?;
-    socket.listen(CONN_BACKLOG as i32)?;
+/// Creates a transparent TCP listener, every worker binding its own to the same address with SO_REUSEPORT
+fn create_tcp_listener(addr: SocketAddr, backlog: u32) -> Result<TcpListener> {
+    let socket = create_transparent_socket(&addr, Type::STREAM, Protocol::TCP)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog as i32)?;
     TcpListener::from_std(socket.into())
 }
 
+/// Rebinds `listeners` to exactly `addrs`, keeping the ones already bound to an address in it
+///
+/// New listeners are all created before the current ones are touched, so on failure `listeners` is left as it was
+fn rebind<L>(
+    listeners: &mut Vec<L>, addrs: &[SocketAddr], local_addr: impl Fn(&L) -> Option<SocketAddr>, create: impl Fn(SocketAddr) -> Result<L>,
+) -> Result<()> {
+    let mut created = addrs
+        .iter()
+        .filter(|addr| !listeners.iter().any(|l| local_addr(l) == Some(**addr)))
+        .map(|addr| create(*addr).map(|l| (*addr, l)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut current = mem::take(listeners);
+
+    for addr in addrs {
+        if let Some(i) = current.iter().position(|l| local_addr(l) == Some(*addr)) {
+            listeners.push(current.swap_remove(i));
+        } else if let Some(i) = created.iter().position(|(a, _)| a == addr) {
+            listeners.push(created.swap_remove(i).1);
+        }
+    }
+
+    Ok(())
+}
+
+/// Rebinds transparent UDP sockets to the listen addresses
+pub(super) fn rebind_udp_socket_fds(fds: &mut Vec<AsyncFd<Socket>>, addrs: &[SocketAddr]) -> Result<()> {
+    rebind(fds, addrs, |fd| fd.get_ref().local_addr().ok()?.as_socket(), create_udp_socket_fd)
+}
+
+/// Rebinds transparent TCP listeners to the listen addresses
+///
+/// * Listeners kept are listened on again, which applies a new backlog to them
+pub(super) fn rebind_tcp_listeners(listeners: &mut Vec<TcpListener>, addrs: &[SocketAddr], backlog: u32) -> Result<()> {
+    rebind(listeners, addrs, |l| l.local_addr().ok(), |addr| create_tcp_listener(addr, backlog))?;
+
+    listeners
+        .iter()
+        .try_for_each(|l| SockRef::from(l).listen(backlog as i32))
+}
+
+/// Sets the options on a TCP socket, leaving the unset ones to the system defaults
+pub(super) fn set_socket_options(sock: SockRef, opts: &SocketOptions) -> Result<()> {
+    if let Some(k) = opts.keepalive {
+        let mut params = TcpKeepalive::new();
+        if let Some(idle) = k.idle {
+            params = params.with_time(idle);
+        }
+        if let Some(interval) = k.interval {
+            params = params.with_interval(interval);
+        }
+        if let Some(count) = k.count {
+            params = params.with_retries(count);
+        }
+        sock.set_tcp_keepalive(&params)?;
+    }
+
+    if let Some(t) = opts.user_timeout {
+        sock.set_tcp_user_timeout(Some(t))?;
+    }
+
+    if let Some(nodelay) = opts.nodelay {
+        sock.set_tcp_nodelay(nodelay)?;
+    }
+
+    if let Some(size) = opts.send_buffer_size {
+        sock.set_send_buffer_size(size)?;
+    }
+
+    if let Some(size) = opts.recv_buffer_size {
+        sock.set_recv_buffer_size(size)?;
+    }
+
+    Ok(())
+}
+
+/// Errors out if the client address can't be spoofed towards the upstream, their IP families differing
+fn check_spoofable(src: &SocketAddr, proxy: &SocketAddr) -> Result<()> {
+    match src.is_ipv6() == proxy.is_ipv6() {
+        true => Ok(()),
+        false => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Can't spoof client {src} towards upstream {proxy} of another IP family"),
+        )),
+    }
+}
+
+/// How an upstream socket leaves the proxy
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub(super) struct Egress {
+    /// Client address the socket is bound to transparently
+    pub(super) spoofed: Option<SocketAddr>,
+    /// Local IP the socket is bound to
+    pub(super) bind_address: Option<IpAddr>,
+    /// Interface the socket is bound to with SO_BINDTODEVICE
+    pub(super) interface: Option<Interface>,
+    /// SO_MARK of the socket, so its traffic isn't intercepted again
+    pub(super) mark: Option<u32>,
+}
+
+impl Egress {
+    /// Egress of the upstream sockets of a rule for a client
+    pub(super) fn new(settings: &Settings, src: &SocketAddr) -> Self {
+        Self {
+            spoofed: settings.spoof_source.then_some(*src),
+            bind_address: settings.bind_address,
+            interface: settings.interface,
+            mark: settings.mark,
+        }
+    }
+
+    /// Egress of the health probes of a rule, which have no client to spoof
+    pub(super) fn probe(settings: &Settings) -> Self {
+        Self {
+            spoofed: None,
+            bind_address: settings.bind_address,
+            interface: settings.interface,
+            mark: settings.mark,
+        }
+    }
+
+    /// Local address to bind to before connecting to `proxy`, an ephemeral port of `bind_address` unless spoofing
+    fn local_addr(&self, proxy: &SocketAddr) -> Result<Option<SocketAddr>> {
+        match (self.spoofed, self.bind_address) {
+            (Some(src), _) => check_spoofable(&src, proxy).map(|_| Some(src)),
+            (None, Some(ip)) if ip.is_ipv6() != proxy.is_ipv6() => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Can't bind to {ip} towards upstream {proxy} of another IP family"),
+            )),
+            (None, ip) => Ok(ip.map(|ip| SocketAddr::new(ip, 0u16))),
+        }
+    }
+
+    /// Sets the mark, interface & transparency of a socket, before it's bound
+    fn apply(&self, sock: SockRef) -> Result<()> {
+        if let Some(m) = self.mark {
+            sock.set_mark(m)?;
+        }
+
+        if let Some(interface) = &self.interface {
+            sock.bind_device(Some(interface.as_bytes()))?;
+        }
+
+        match self.spoofed {
+            Some(SocketAddr::V4(_)) => sock.set_ip_transparent_v4(true),
+            Some(SocketAddr::V6(_)) => sock.set_ip_transparent_v6(true),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Whether forwarding to `proxy` would come back to the proxy
+///
+/// * The upstream is one of the listen addresses, or on the loopback when listening on the unspecified address
+/// * The upstream of a pool is the original destination itself, which is intercepted again
+pub(super) fn is_loop(proxy: &SocketAddr, orig_dst: &SocketAddr, target: &Target, listen: &[SocketAddr]) -> bool {
+    let listened = listen
+        .iter()
+        .any(|l| l == proxy || (l.port() == proxy.port() && l.ip().is_unspecified() && proxy.ip().is_loopback()));
+
+    listened || (matches!(target, Target::Upstream(_)) && proxy == orig_dst)
+}
+
+/// Connects a TCP socket with the options set before the handshake, so buffer sizes are accounted for in the window scale
+///
+/// * The socket is bound as set by `egress` first
+pub(super) async fn connect_tcp(addr: SocketAddr, opts: &SocketOptions, egress: &Egress) -> Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    set_socket_options(SockRef::from(&socket), opts)?;
+    egress.apply(SockRef::from(&socket))?;
+
+    if let Some(local) = egress.local_addr(&addr)? {
+        socket.set_reuseaddr(true)?;
+        socket.bind(local)?;
+    }
+
+    socket.connect(addr).await
+}
+
+/// Connects to an upstream of the target for a client, returning the connection, the upstream & its lease
+///
+/// * Without connect retry, a single attempt is made within the connect timeout of `settings`
+/// * With it, another untried healthy member is picked after a failure, else the same one is retried after a backoff, each attempt still bound by the connect timeout
+/// * The socket options & egress of `settings` are set on the upstream socket, bound to the client address if it spoofs the source
+/// * An upstream looping back to the proxy through `listen` or `orig_dst` counts as a failed attempt
+pub(super) async fn connect_tcp_upstream(
+    target: &Target, src: &SocketAddr, orig_dst: &SocketAddr, settings: &Settings, listen: &[SocketAddr],
+) -> Result<(TcpStream, SocketAddr, Option<Lease>)> {
+    let connect_timeout = settings.connect_timeout;
+    let (mut proxy, mut lease) = target
+        .resolve(src, orig_dst)
+        .ok_or_else(|| Error::new(ErrorKind::NotConnected, "No healthy upstream"))?;
+
+    let pool = match target {
+        Target::Upstream(pool) => Some(pool),
+        Target::Passthrough => None,
+    };
+    let retry = pool.and_then(|p| p.retry());
+
+    let (attempts, deadline) = match retry {
+        Some(r) => (r.attempts, Instant::now() + r.deadline),
+        None => (1u32, Instant::now() + connect_timeout),
+    };
+    let mut backoff = retry.map(|r| r.backoff).unwrap_or_default();
+    let egress = Egress::new(settings, src);
+    let mut tried = Vec::new();
+    let mut attempt = 1u32;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        let connect = connect_tcp(proxy, &settings.socket_options, &egress);
+
+        let err = match is_loop(&proxy, orig_dst, target, listen) {
+            true => Error::new(ErrorKind::InvalidInput, format!("Upstream {proxy} loops back to the proxy")),
+            false => match timeout(connect_timeout.min(remaining), connect).await {
+                Ok(Ok(stream)) => return Ok((stream, proxy, lease)),
+                Ok(Err(e)) => e,
+                Err(_) => Error::new(ErrorKind::TimedOut, format!("Timed out while trying to connect to upstream {proxy}")),
+            },
+        };
+
+        if attempt >= attempts || Instant::now() >= deadline {
+            return Err(Error::new(err.kind(), format!("{err} (attempt {attempt} of {attempts} to {proxy})")));
+        }
+
+        warn!("Attempt {attempt} to connect to upstream {proxy} failed - {err}");
+        attempt += 1u32;
+        tried.push(proxy);
+
+        match pool.and_then(|p| p.pick_untried(src, &tried)) {
+            Some(l) => {
+                proxy = l.addr();
+                lease = Some(l);
+            },
+            None => {
+                sleep(backoff.min(deadline.saturating_duration_since(Instant::now()))).await;
+                backoff *= 2u32;
+            },
+        };
+    }
+}
+
+/// Makes the connection be reset (RST) instead of gracefully closed (FIN) once dropped
+pub(super) fn reset_on_close(stream: &TcpStream) {
+    if let Err(e) = SockRef::from(stream).set_linger(Some(Duration::ZERO)) {
+        error!("Failed to set SO_LINGER - {e}");
+    }
+}
+
+/// Creates a UDP socket connected to the upstream, so that only its replies are received
+///
+/// * The socket is bound as set by `egress`, else to an ephemeral local address
+pub(super) async fn create_udp_upstream_socket(proxy: &SocketAddr, egress: &Egress) -> Result<UdpSocket> {
+    let unspecified = match proxy {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let local = egress
+        .local_addr(proxy)?
+        .unwrap_or(SocketAddr::new(unspecified, 0u16));
+
+    let socket = Socket::new(Domain::for_address(*proxy), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_nonblocking(true)?;
+    egress.apply(SockRef::from(&socket))?;
+    socket.set_reuse_address(egress.spoofed.is_some())?;
+    socket.bind(&local.into())?;
+
+    let socket = UdpSocket::from_std(socket.into())?;
+    socket.connect(proxy).await?;
+    Ok(socket)
+}
+
+/// Creates a transparent UDP socket bound to the original destination, used to send replies back to the client
+pub(super) fn create_udp_reply_socket(orig_dst: SocketAddr) -> Result<UdpSocket> {
+    let socket = create_transparent_socket(&orig_dst, Type::DGRAM, Protocol::UDP)?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&orig_dst.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
 trait ExtendedSocket {
     fn set_recv_orig_dst_addr(&self, recv: bool) -> Result<()>;
+    fn set_recv_orig_dst_addr_v6(&self, recv: bool) -> Result<()>;
 }
 
 impl ExtendedSocket for Socket {
@@ -106,6 +577,23 @@ impl ExtendedSocket for Socket {
             _ => Ok(()),
         }
     }
+
+    fn set_recv_orig_dst_addr_v6(&self, recv: bool) -> Result<()> {
+        let recv = recv as c_int;
+
+        match unsafe {
+            setsockopt(
+                self.as_raw_fd(),
+                IPPROTO_IPV6,
+                IPV6_RECVORIGDSTADDR,
+                &recv as *const _ as *const c_void,
+                size_of::<c_int>() as socklen_t,
+            )
+        } {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -113,14 +601,18 @@ impl ExtendedSocket for Socket {
 mod tests {
 
     use libc::getsockopt;
-    use std::net::Ipv4Addr;
-    use tokio::net::UdpSocket;
+    use std::{cell::RefCell, sync::Arc};
+
+    use crate::utils::{
+        structs::{Keepalive, fixtures::default_settings},
+        upstreams::{Host, Member, Retry, Strategy, UpstreamPool},
+    };
 
     use super::*;
 
     #[tokio::test]
-    async fn test_recvfrom_cmsg() {
-        let mut buf = [0u8; 128];
+    async fn test_recvmmsg_cmsg() {
+        let mut bufs = [[0u8; 128]; 4];
         let payload = b"payload";
 
         // OK
@@ -128,10 +620,10 @@ mod tests {
         sock1.set_recv_orig_dst_addr(true).unwrap();
         sock1.set_nonblocking(true).unwrap();
         sock1
-            .bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0u16).into())
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0u16).into())
             .unwrap();
 
-        let local_addr1 = sock1.local_addr().unwrap().as_socket_ipv4().unwrap();
+        let local_addr1 = sock1.local_addr().unwrap().as_socket().unwrap();
         let fd1: AsyncFd<Socket> = AsyncFd::new(sock1).unwrap();
         let send_sock1 = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
 
@@ -139,35 +631,55 @@ mod tests {
         assert_eq!(size1, payload.len());
 
         let _ = fd1.readable().await.unwrap();
-        let (src, len, orig_dst) = recvfrom_cmsg(&fd1, &mut buf).unwrap();
+        let received = recvmmsg_cmsg(&fd1, &mut bufs);
+        assert_eq!(1usize, received.len());
+        let (src, len, orig_dst) = received[0].unwrap();
         assert_eq!(len, payload.len());
-        assert_eq!(&buf[..len], payload);
-        assert_eq!(orig_dst.ip(), local_addr1.ip());
-        assert_eq!(orig_dst.port(), local_addr1.port());
-        assert_eq!(src.ip(), &Ipv4Addr::LOCALHOST);
+        assert_eq!(&bufs[0][..len], payload);
+        assert_eq!(orig_dst, local_addr1);
+        assert_eq!(src.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        // Batch, one datagram per buffer
+        for i in 0u8..6u8 {
+            send_sock1.send_to(&[i; 3], &local_addr1).await.unwrap();
+        }
+
+        let _ = fd1.readable().await.unwrap();
+        let received = recvmmsg_cmsg(&fd1, &mut bufs);
+        assert_eq!(bufs.len(), received.len());
+        for (i, (buf, datagram)) in bufs.iter().zip(received).enumerate() {
+            let (src, len, orig_dst) = datagram.unwrap();
+            assert_eq!(&buf[..len], &[i as u8; 3]);
+            assert_eq!(orig_dst, local_addr1);
+            assert_eq!(src, send_sock1.local_addr().unwrap());
+        }
+
+        let received = recvmmsg_cmsg(&fd1, &mut bufs);
+        assert_eq!(2usize, received.len());
+        assert_eq!(&bufs[1][..3], &[5u8; 3]);
 
         // EWOULDBLOCK
         let sock2 = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
         sock2.set_recv_orig_dst_addr(true).unwrap();
         sock2.set_nonblocking(true).unwrap();
         sock2
-            .bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0u16).into())
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0u16).into())
             .unwrap();
 
         let fd2 = AsyncFd::new(sock2).unwrap();
-        let res1 = recvfrom_cmsg(&fd2, &mut buf);
-        assert!(res1.is_none());
+        let res1 = recvmmsg_cmsg(&fd2, &mut bufs);
+        assert!(res1.is_empty());
 
         // No RECVORIGDSTADDR
         let sock3 = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
         sock3.set_nonblocking(true).unwrap();
         sock3
-            .bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0u16).into())
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0u16).into())
             .unwrap();
 
-        let local_addr2 = sock3.local_addr().unwrap().as_socket_ipv4().unwrap();
+        let local_addr2 = sock3.local_addr().unwrap().as_socket().unwrap();
         let fd3 = AsyncFd::new(sock3).unwrap();
-        let send_sock2 = UdpSocket::bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0u16))
+        let send_sock2 = UdpSocket::bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0u16))
             .await
             .unwrap();
 
@@ -175,8 +687,463 @@ mod tests {
         assert_eq!(size2, payload.len());
 
         let _ = fd3.readable().await.unwrap();
-        let res2 = recvfrom_cmsg(&fd3, &mut buf);
-        assert!(res2.is_none());
+        let res2 = recvmmsg_cmsg(&fd3, &mut bufs);
+        assert_eq!(vec![None], res2);
+
+        // OK IPv6
+        let sock4 = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        sock4.set_recv_orig_dst_addr_v6(true).unwrap();
+        sock4.set_nonblocking(true).unwrap();
+        sock4
+            .bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0u16).into())
+            .unwrap();
+
+        let local_addr3 = sock4.local_addr().unwrap().as_socket().unwrap();
+        let fd4 = AsyncFd::new(sock4).unwrap();
+        let send_sock3 = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0u16)).await.unwrap();
+
+        let size3 = send_sock3.send_to(payload, &local_addr3).await.unwrap();
+        assert_eq!(size3, payload.len());
+
+        let _ = fd4.readable().await.unwrap();
+        let (src, len, orig_dst) = recvmmsg_cmsg(&fd4, &mut bufs)[0].unwrap();
+        assert_eq!(len, payload.len());
+        assert_eq!(&bufs[0][..len], payload);
+        assert_eq!(orig_dst, local_addr3);
+        assert_eq!(src.ip(), IpAddr::V6(Ipv6Addr::LOCALHOST));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_datagrams() {
+        let mut bufs = [[0u8; 16]; 2];
+
+        let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        sock.set_recv_orig_dst_addr(true).unwrap();
+        sock.set_nonblocking(true).unwrap();
+        sock.bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0u16).into())
+            .unwrap();
+
+        let local_addr = sock.local_addr().unwrap().as_socket().unwrap();
+        let fd = AsyncFd::new(sock).unwrap();
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+
+        // client leg
+        let truncated = TRUNCATED_DATAGRAMS.load(Ordering::Relaxed);
+        client.send_to(&[1u8; 17], &local_addr).await.unwrap();
+        client.send_to(&[2u8; 16], &local_addr).await.unwrap();
+
+        let _ = fd.readable().await.unwrap();
+        let received = recvmmsg_cmsg(&fd, &mut bufs);
+        assert_eq!(2usize, received.len());
+        assert!(received[0].is_none());
+        assert_eq!(16usize, received[1].unwrap().1);
+        assert!(TRUNCATED_DATAGRAMS.load(Ordering::Relaxed) > truncated);
+
+        // upstream leg
+        let upstream = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+        upstream
+            .connect(client.local_addr().unwrap())
+            .await
+            .unwrap();
+        client
+            .connect(upstream.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let truncated = TRUNCATED_DATAGRAMS.load(Ordering::Relaxed);
+        client.send(&[3u8; 17]).await.unwrap();
+        client.send(&[4u8; 16]).await.unwrap();
+
+        upstream.readable().await.unwrap();
+        let err = try_recv_whole(&upstream, &mut bufs[0]).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert!(TRUNCATED_DATAGRAMS.load(Ordering::Relaxed) > truncated);
+
+        assert_eq!(16usize, recv_whole(&upstream, &mut bufs[0]).await.unwrap());
+        assert_eq!([4u8; 16], bufs[0]);
+    }
+
+    #[tokio::test]
+    async fn test_sendmmsg_to() {
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+
+        let datagrams = (0u8..40u8)
+            .map(|i| vec![i; 1usize + i as usize])
+            .collect::<Vec<_>>();
+        let slices = datagrams.iter().map(|d| d.as_slice()).collect::<Vec<_>>();
+        sendmmsg_to(&sender, receiver.local_addr().unwrap(), &slices)
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 64];
+        for datagram in &datagrams {
+            let (len, src) = receiver.recv_from(&mut buf).await.unwrap();
+            assert_eq!(datagram.as_slice(), &buf[..len]);
+            assert_eq!(sender.local_addr().unwrap(), src);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_readable() {
+        let payload = b"payload";
+        let fds: Vec<AsyncFd<Socket>> = (0..2)
+            .map(|_| {
+                let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+                sock.set_nonblocking(true).unwrap();
+                sock.bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0u16).into())
+                    .unwrap();
+                AsyncFd::new(sock).unwrap()
+            })
+            .collect();
+
+        let local_addr = fds[1].get_ref().local_addr().unwrap().as_socket().unwrap();
+        let send_sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+        send_sock.send_to(payload, &local_addr).await.unwrap();
+
+        let (i, guard) = udp_readable(&fds).await;
+        assert_eq!(1usize, i);
+        assert!(guard.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_rebind_tcp_listeners() {
+        let free_addr = |ip: IpAddr| {
+            let sock = std::net::TcpListener::bind((ip, 0u16)).unwrap();
+            sock.local_addr().unwrap()
+        };
+        let addr1 = free_addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let addr2 = free_addr(IpAddr::V6(Ipv6Addr::LOCALHOST));
+        let local_addrs = |ls: &Vec<TcpListener>| {
+            ls.iter()
+                .map(|l| l.local_addr().unwrap())
+                .collect::<Vec<_>>()
+        };
+        // like the transparent listeners, with SO_REUSEPORT
+        let created = RefCell::new(Vec::new());
+        let create = |addr: SocketAddr| {
+            created.borrow_mut().push(addr);
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+            socket.set_reuse_port(true)?;
+            socket.bind(&addr.into())?;
+            socket.listen(128i32)?;
+            socket.set_nonblocking(true)?;
+            TcpListener::from_std(socket.into())
+        };
+
+        let mut listeners = Vec::new();
+        rebind(&mut listeners, &[addr1], |l: &TcpListener| l.local_addr().ok(), create).unwrap();
+        assert_eq!(vec![addr1], local_addrs(&listeners));
+        let fd1 = listeners[0].as_raw_fd();
+
+        // existing listener is kept as is, where binding again would succeed & leave a second socket sharing its address
+        rebind(&mut listeners, &[addr2, addr1], |l| l.local_addr().ok(), create).unwrap();
+        assert_eq!(vec![addr2, addr1], local_addrs(&listeners));
+        assert_eq!(fd1, listeners[1].as_raw_fd());
+        assert_eq!(vec![addr1, addr2], *created.borrow());
+
+        rebind(&mut listeners, &[addr2], |l| l.local_addr().ok(), create).unwrap();
+        assert_eq!(vec![addr2], local_addrs(&listeners));
+
+        // failure leaves listeners untouched
+        let taken = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16)).unwrap();
+        let taken_addr = taken.local_addr().unwrap();
+        assert!(rebind(&mut listeners, &[taken_addr], |l| l.local_addr().ok(), create).is_err());
+        assert_eq!(vec![addr2], local_addrs(&listeners));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_accept() {
+        let listeners = [
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+                .await
+                .unwrap(),
+            TcpListener::bind((Ipv6Addr::LOCALHOST, 0u16))
+                .await
+                .unwrap(),
+        ];
+
+        let local_addr = listeners[1].local_addr().unwrap();
+        let _client = TcpStream::connect(local_addr).await.unwrap();
+
+        let (i, result) = tcp_accept(&listeners).await;
+        assert_eq!(1usize, i);
+        assert_eq!(IpAddr::V6(Ipv6Addr::LOCALHOST), result.unwrap().1.ip());
+    }
+
+    #[tokio::test]
+    async fn test_connect_tcp_upstream() {
+        let src = SocketAddr::from((Ipv4Addr::LOCALHOST, 40000u16));
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+            .await
+            .unwrap();
+        let live = listener.local_addr().unwrap();
+        let dead = {
+            let l = TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+                .await
+                .unwrap();
+            l.local_addr().unwrap()
+        };
+        let member = |addr: SocketAddr| Member {
+            host: Host::Ip(addr.ip()),
+            port: addr.port(),
+            weight: 1u32,
+        };
+        let retry = Retry {
+            attempts: 2u32,
+            backoff: Duration::from_millis(10u64),
+            deadline: Duration::from_secs(1u64),
+        };
+        let target = |retry: Option<Retry>| {
+            Target::Upstream(Arc::new(
+                UpstreamPool::new(Strategy::RoundRobin, &[member(dead), member(live)], None, retry).unwrap(),
+            ))
+        };
+
+        let settings = Settings {
+            socket_options: SocketOptions {
+                nodelay: Some(true),
+                ..Default::default()
+            },
+            ..default_settings()
+        };
+
+        let orig = SocketAddr::from((Ipv4Addr::new(192u8, 0u8, 2u8, 1u8), 80u16));
+
+        // the first pick is the dead member
+        assert!(
+            connect_tcp_upstream(&target(None), &src, &orig, &settings, &[])
+                .await
+                .is_err()
+        );
+
+        let (_, proxy, lease) = connect_tcp_upstream(&target(Some(retry.clone())), &src, &orig, &settings, &[])
+            .await
+            .unwrap();
+        assert_eq!(live, proxy);
+        assert_eq!(live, lease.unwrap().addr());
+
+        // the live member loops back through a listen address or the original destination
+        assert!(
+            connect_tcp_upstream(&target(Some(retry.clone())), &src, &orig, &settings, &[live])
+                .await
+                .is_err()
+        );
+        assert!(
+            connect_tcp_upstream(&target(Some(retry.clone())), &src, &live, &settings, &[])
+                .await
+                .is_err()
+        );
+
+        let only_dead = Target::Upstream(Arc::new(
+            UpstreamPool::new(Strategy::RoundRobin, &[member(dead)], None, Some(retry)).unwrap(),
+        ));
+        assert!(
+            connect_tcp_upstream(&only_dead, &src, &orig, &settings, &[])
+                .await
+                .is_err()
+        );
+
+        let (stream, proxy, lease) = connect_tcp_upstream(&Target::Passthrough, &src, &live, &settings, &[])
+            .await
+            .unwrap();
+        assert_eq!(live, proxy);
+        assert!(lease.is_none());
+        assert!(stream.nodelay().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_set_socket_options() {
+        let socket = TcpSocket::new_v4().unwrap();
+        let sock = SockRef::from(&socket);
+        let opts = SocketOptions {
+            keepalive: Some(Keepalive {
+                idle: Some(Duration::from_secs(60u64)),
+                interval: Some(Duration::from_secs(10u64)),
+                count: Some(5u32),
+            }),
+            user_timeout: Some(Duration::from_secs(30u64)),
+            nodelay: Some(true),
+            send_buffer_size: Some(65536usize),
+            recv_buffer_size: None,
+        };
+        let recv_buffer_size = sock.recv_buffer_size().unwrap();
+
+        set_socket_options(SockRef::from(&socket), &opts).unwrap();
+        assert!(sock.keepalive().unwrap());
+        assert_eq!(Duration::from_secs(60u64), sock.tcp_keepalive_time().unwrap());
+        assert_eq!(Duration::from_secs(10u64), sock.tcp_keepalive_interval().unwrap());
+        assert_eq!(5u32, sock.tcp_keepalive_retries().unwrap());
+        assert_eq!(Some(Duration::from_secs(30u64)), sock.tcp_user_timeout().unwrap());
+        assert!(sock.tcp_nodelay().unwrap());
+        // the kernel doubles the requested size for its bookkeeping
+        assert!(sock.send_buffer_size().unwrap() >= 65536usize);
+        assert_eq!(recv_buffer_size, sock.recv_buffer_size().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_source() {
+        let free_port = || {
+            std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16))
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port()
+        };
+        let client = SocketAddr::new(Ipv4Addr::new(127u8, 0u8, 0u8, 5u8).into(), free_port());
+        let spoofed = Egress {
+            spoofed: Some(client),
+            ..Default::default()
+        };
+
+        let upstream = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+        let socket = create_udp_upstream_socket(&upstream.local_addr().unwrap(), &spoofed)
+            .await
+            .unwrap();
+        socket.send(b"spoofed").await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+        assert_eq!(b"spoofed", &buf[..len]);
+        assert_eq!(client, from);
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+            .await
+            .unwrap();
+        let _stream = connect_tcp(listener.local_addr().unwrap(), &SocketOptions::default(), &spoofed)
+            .await
+            .unwrap();
+        assert_eq!(client, listener.accept().await.unwrap().1);
+
+        // no spoofing across IP families
+        let v6_upstream = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 53u16);
+        assert!(
+            create_udp_upstream_socket(&v6_upstream, &spoofed)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_mark() {
+        let upstream = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+        let marked = |mark: u32| Egress {
+            mark: Some(mark),
+            ..Default::default()
+        };
+
+        // SO_MARK needs CAP_NET_ADMIN
+        let socket = match create_udp_upstream_socket(&upstream.local_addr().unwrap(), &marked(0x3001u32)).await {
+            Ok(s) => s,
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => return,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(0x3001u32, SockRef::from(&socket).mark().unwrap());
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+            .await
+            .unwrap();
+        let stream = connect_tcp(listener.local_addr().unwrap(), &SocketOptions::default(), &marked(7u32))
+            .await
+            .unwrap();
+        assert_eq!(7u32, SockRef::from(&stream).mark().unwrap());
+
+        let socket = create_udp_upstream_socket(&upstream.local_addr().unwrap(), &Egress::default())
+            .await
+            .unwrap();
+        assert_eq!(0u32, SockRef::from(&socket).mark().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_bind_address() {
+        let bound = Egress {
+            bind_address: Some(Ipv4Addr::new(127u8, 0u8, 0u8, 7u8).into()),
+            ..Default::default()
+        };
+
+        let upstream = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+        let socket = create_udp_upstream_socket(&upstream.local_addr().unwrap(), &bound)
+            .await
+            .unwrap();
+        socket.send(b"bound").await.unwrap();
+        let (_, from) = upstream.recv_from(&mut [0u8; 16]).await.unwrap();
+        assert_eq!(bound.bind_address, Some(from.ip()));
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+            .await
+            .unwrap();
+        let _stream = connect_tcp(listener.local_addr().unwrap(), &SocketOptions::default(), &bound)
+            .await
+            .unwrap();
+        assert_eq!(bound.bind_address, Some(listener.accept().await.unwrap().1.ip()));
+
+        // no binding across IP families
+        let v6_upstream = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 53u16);
+        assert!(
+            create_udp_upstream_socket(&v6_upstream, &bound)
+                .await
+                .is_err()
+        );
+
+        // SO_BINDTODEVICE may need CAP_NET_RAW
+        let on_loopback = Egress {
+            interface: Some(Interface::try_from(String::from("lo")).unwrap()),
+            ..Default::default()
+        };
+        match create_udp_upstream_socket(&upstream.local_addr().unwrap(), &on_loopback).await {
+            Ok(socket) => assert_eq!(Some(b"lo".to_vec()), SockRef::from(&socket).device().unwrap()),
+            Err(e) => assert_eq!(ErrorKind::PermissionDenied, e.kind()),
+        };
+    }
+
+    #[test]
+    fn test_is_loop() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let pool = Target::Upstream(Arc::new(
+            UpstreamPool::new(
+                Strategy::RoundRobin,
+                &[Member {
+                    host: Host::Ip(addr("10.0.0.1:53").ip()),
+                    port: 53u16,
+                    weight: 1u32,
+                }],
+                None,
+                None,
+            )
+            .unwrap(),
+        ));
+        let listen = [addr("127.0.0.1:8080"), addr("0.0.0.0:8081")];
+
+        assert!(!is_loop(&addr("10.0.0.1:53"), &addr("1.1.1.1:53"), &pool, &listen));
+        assert!(is_loop(&addr("127.0.0.1:8080"), &addr("1.1.1.1:53"), &pool, &listen));
+        assert!(is_loop(&addr("127.0.0.2:8081"), &addr("1.1.1.1:53"), &pool, &listen));
+        assert!(!is_loop(&addr("10.0.0.1:8081"), &addr("1.1.1.1:53"), &pool, &listen));
+        assert!(is_loop(&addr("1.1.1.1:53"), &addr("1.1.1.1:53"), &pool, &listen));
+
+        // passthrough always connects to the original destination
+        assert!(!is_loop(&addr("1.1.1.1:53"), &addr("1.1.1.1:53"), &Target::Passthrough, &listen));
+        assert!(is_loop(&addr("127.0.0.1:8080"), &addr("127.0.0.1:8080"), &Target::Passthrough, &listen));
+    }
+
+    #[tokio::test]
+    async fn test_reset_on_close() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+            .await
+            .unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        reset_on_close(&client);
+        assert_eq!(Some(Duration::ZERO), SockRef::from(&client).linger().unwrap());
+        drop(client);
+
+        let mut buf = [0u8; 16];
+        let err = tokio::io::AsyncReadExt::read(&mut server, &mut buf)
+            .await
+            .unwrap_err();
+        assert_eq!(ErrorKind::ConnectionReset, err.kind());
     }
 
     #[test]
@@ -215,5 +1182,21 @@ mod tests {
         };
         assert_eq!(0, rc2);
         assert_eq!(0, value);
+
+        // set IPv6
+        let sock3 = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        sock3.set_recv_orig_dst_addr_v6(true).unwrap();
+
+        let rc3 = unsafe {
+            getsockopt(
+                sock3.as_raw_fd(),
+                IPPROTO_IPV6,
+                IPV6_RECVORIGDSTADDR,
+                &mut value as *mut _ as *mut c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(0, rc3);
+        assert_eq!(1, value);
     }
 }
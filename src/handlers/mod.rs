@@ -1,6 +1,13 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+#[cfg(feature = "bench")]
+pub mod bench;
 pub(super) mod constants;
 pub(super) mod forwarders;
+pub(super) mod health_checker;
 pub(self) mod helpers;
+mod proxy_protocol;
+mod relay;
+pub(super) mod resolver;
 pub(super) mod signal_handler;
+pub(super) mod udp_sessions;
@@ -0,0 +1,344 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::utils::structs::{IpNet, ProxyProtocol};
+
+use super::constants::PROXY_HEADER_TIMEOUT;
+
+/// Signature opening every v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Version 2 & PROXY command
+const V2_PROXY: u8 = 0x21u8;
+
+/// Unknown address family & transport, the receiver keeping the real connection endpoints
+const V2_UNSPEC: u8 = 0x00u8;
+
+/// Longest v1 header, CRLF included
+const V1_MAX_LEN: usize = 107;
+
+/// Transport of the connection announced by a header
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Transport {
+    Stream,
+    Datagram,
+}
+
+/// PROXY protocol header announcing a connection from `src` to `dst`
+///
+/// * v1 is text & only describes TCP connections
+/// * Endpoints of different IP families are announced as unknown
+pub(super) fn header(version: ProxyProtocol, transport: Transport, src: &SocketAddr, dst: &SocketAddr) -> Vec<u8> {
+    match version {
+        ProxyProtocol::V1 => v1(src, dst),
+        ProxyProtocol::V2 => v2(transport, src, dst),
+    }
+}
+
+fn v1(src: &SocketAddr, dst: &SocketAddr) -> Vec<u8> {
+    let proto = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => "TCP4",
+        (IpAddr::V6(_), IpAddr::V6(_)) => "TCP6",
+        _ => return b"PROXY UNKNOWN\r\n".to_vec(),
+    };
+
+    format!("PROXY {proto} {} {} {} {}\r\n", src.ip(), dst.ip(), src.port(), dst.port()).into_bytes()
+}
+
+fn v2(transport: Transport, src: &SocketAddr, dst: &SocketAddr) -> Vec<u8> {
+    let proto = match transport {
+        Transport::Stream => 0x01u8,
+        Transport::Datagram => 0x02u8,
+    };
+
+    let (family, addrs) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (0x10u8 | proto, [s.octets().as_slice(), d.octets().as_slice()].concat()),
+        (IpAddr::V6(s), IpAddr::V6(d)) => (0x20u8 | proto, [s.octets().as_slice(), d.octets().as_slice()].concat()),
+        _ => (V2_UNSPEC, Vec::new()),
+    };
+
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[V2_PROXY, family]);
+
+    match family {
+        V2_UNSPEC => header.extend_from_slice(&0u16.to_be_bytes()),
+        _ => {
+            header.extend_from_slice(&(addrs.len() as u16 + 4u16).to_be_bytes());
+            header.extend_from_slice(&addrs);
+            header.extend_from_slice(&src.port().to_be_bytes());
+            header.extend_from_slice(&dst.port().to_be_bytes());
+        },
+    };
+
+    header
+}
+
+fn malformed(details: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Malformed PROXY protocol header - {}", details.into()))
+}
+
+/// Client address & original destination announced by the PROXY protocol header opening a stream
+///
+/// * [`None`] for a LOCAL command or an unknown address family, the connection endpoints being kept
+/// * Only the header is consumed, the data following it is left to be relayed
+async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await?;
+
+        let mut addrs = vec![0u8; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        stream.read_exact(&mut addrs).await?;
+        return parse_v2(fixed[0], fixed[1], &addrs);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(malformed("missing signature"));
+    }
+
+    // read byte by byte not to consume any data following the header
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(malformed(format!("v1 header longer than {V1_MAX_LEN} bytes")));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    parse_v1(&line[..line.len() - 2usize])
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    let line = std::str::from_utf8(line).map_err(|_| malformed("non-ASCII v1 header"))?;
+
+    match line.split(' ').collect::<Vec<_>>().as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src_ip, dst_ip, src_port, dst_port] => {
+            let ip = |s: &str| match s.parse::<IpAddr>() {
+                Ok(ip) if ip.is_ipv4() == (*proto == "TCP4") => Ok(ip),
+                _ => Err(malformed(format!("invalid {proto} address \"{s}\""))),
+            };
+            let port = |s: &str| {
+                s.parse::<u16>()
+                    .map_err(|_| malformed(format!("invalid port \"{s}\"")))
+            };
+
+            Ok(Some((
+                SocketAddr::new(ip(src_ip)?, port(src_port)?),
+                SocketAddr::new(ip(dst_ip)?, port(dst_port)?),
+            )))
+        },
+        _ => Err(malformed(format!("invalid v1 header \"{line}\""))),
+    }
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, addrs: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    if ver_cmd >> 4u8 != 2u8 {
+        return Err(malformed(format!("unsupported version {}", ver_cmd >> 4u8)));
+    }
+
+    let port = |at: usize| u16::from_be_bytes([addrs[at], addrs[at + 1usize]]);
+    let v4 = |at: usize| IpAddr::V4(Ipv4Addr::new(addrs[at], addrs[at + 1usize], addrs[at + 2usize], addrs[at + 3usize]));
+    let v6 = |at: usize| {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&addrs[at..at + 16usize]);
+        IpAddr::V6(Ipv6Addr::from(octets))
+    };
+
+    // LOCAL connections come from the load balancer itself
+    match (ver_cmd & 0x0fu8, family) {
+        (0x00u8, _) => Ok(None),
+        (0x01u8, 0x11u8) if addrs.len() >= 12usize => Ok(Some((
+            SocketAddr::new(v4(0usize), port(8usize)),
+            SocketAddr::new(v4(4usize), port(10usize)),
+        ))),
+        (0x01u8, 0x21u8) if addrs.len() >= 36usize => Ok(Some((
+            SocketAddr::new(v6(0usize), port(32usize)),
+            SocketAddr::new(v6(16usize), port(34usize)),
+        ))),
+        (0x01u8, 0x11u8 | 0x21u8) => Err(malformed("truncated addresses")),
+        (0x01u8, V2_UNSPEC) => Ok(None),
+        (0x01u8, f) => Err(malformed(format!("unsupported address family & transport {f:#04x}"))),
+        (cmd, _) => Err(malformed(format!("unknown command {cmd:#x}"))),
+    }
+}
+
+/// Client address & original destination of a connection from a load balancer, read from its PROXY protocol header
+///
+/// * Peers outside of `trusted` are refused before anything is read, as they could claim any addresses
+/// * The connection endpoints are kept when the header doesn't carry addresses
+/// * The header must arrive within [`PROXY_HEADER_TIMEOUT`]
+pub(super) async fn accept(stream: &mut TcpStream, trusted: &[IpNet]) -> Result<(SocketAddr, SocketAddr)> {
+    let peer = stream.peer_addr()?;
+    if !trusted.iter().any(|net| net.contains(&peer.ip())) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{peer} isn't trusted to send a PROXY protocol header"),
+        ));
+    }
+
+    match timeout(PROXY_HEADER_TIMEOUT, read_header(stream)).await {
+        Ok(Ok(Some(endpoints))) => Ok(endpoints),
+        Ok(Ok(None)) => Ok((stream.peer_addr()?, stream.local_addr()?)),
+        Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => Err(malformed("connection closed before the end of the header")),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "Timed out waiting for the PROXY protocol header")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::{net::Ipv4Addr, str::FromStr};
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_header_v1() {
+        assert_eq!(
+            b"PROXY TCP4 192.168.1.10 10.0.0.1 40000 80\r\n".to_vec(),
+            header(ProxyProtocol::V1, Transport::Stream, &addr("192.168.1.10:40000"), &addr("10.0.0.1:80"))
+        );
+        assert_eq!(
+            b"PROXY TCP6 2001:db8::1 2001:db8::2 40000 443\r\n".to_vec(),
+            header(
+                ProxyProtocol::V1,
+                Transport::Stream,
+                &addr("[2001:db8::1]:40000"),
+                &addr("[2001:db8::2]:443")
+            )
+        );
+        assert_eq!(
+            b"PROXY UNKNOWN\r\n".to_vec(),
+            header(
+                ProxyProtocol::V1,
+                Transport::Stream,
+                &addr("192.168.1.10:40000"),
+                &addr("[2001:db8::2]:443")
+            )
+        );
+    }
+
+    #[test]
+    fn test_header_v2() {
+        let tcp4 = header(ProxyProtocol::V2, Transport::Stream, &addr("192.168.1.10:40000"), &addr("10.0.0.1:80"));
+        assert_eq!(&V2_SIGNATURE, &tcp4[..12]);
+        assert_eq!(
+            [
+                0x21u8, 0x11u8, 0u8, 12u8, 192u8, 168u8, 1u8, 10u8, 10u8, 0u8, 0u8, 1u8, 0x9cu8, 0x40u8, 0u8, 80u8
+            ],
+            tcp4[12..]
+        );
+
+        let udp6 = header(
+            ProxyProtocol::V2,
+            Transport::Datagram,
+            &addr("[2001:db8::1]:53"),
+            &addr("[2001:db8::2]:53"),
+        );
+        assert_eq!([0x21u8, 0x22u8, 0u8, 36u8], udp6[12..16]);
+        assert_eq!(16usize + 36usize, udp6.len());
+        assert_eq!([0u8, 53u8, 0u8, 53u8], udp6[48..]);
+
+        let mixed = header(
+            ProxyProtocol::V2,
+            Transport::Stream,
+            &addr("192.168.1.10:40000"),
+            &addr("[2001:db8::2]:443"),
+        );
+        assert_eq!([0x21u8, 0x00u8, 0u8, 0u8], mixed[12..]);
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let src = addr("192.168.1.10:40000");
+        let dst = addr("10.0.0.1:80");
+
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+            let mut data = header(version, Transport::Stream, &src, &dst);
+            data.extend_from_slice(b"GET / HTTP/1.1");
+
+            let mut stream = data.as_slice();
+            assert_eq!(Some((src, dst)), read_header(&mut stream).await.unwrap());
+            assert_eq!(b"GET / HTTP/1.1", stream);
+        }
+
+        let v6 = (addr("[2001:db8::1]:40000"), addr("[2001:db8::2]:443"));
+        let data = header(ProxyProtocol::V2, Transport::Stream, &v6.0, &v6.1);
+        assert_eq!(Some(v6), read_header(&mut data.as_slice()).await.unwrap());
+
+        let mut unknown = b"PROXY UNKNOWN\r\nhello".as_slice();
+        assert_eq!(None, read_header(&mut unknown).await.unwrap());
+        assert_eq!(b"hello", unknown);
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20u8, 0x00u8, 0u8, 0u8]);
+        assert_eq!(None, read_header(&mut local.as_slice()).await.unwrap());
+
+        for malformed in [
+            b"GET / HTTP/1.1\r\n".as_slice(),
+            b"PROXY TCP4 10.0.0.1 10.0.0.2 1\r\n",
+            b"PROXY TCP4 2001:db8::1 10.0.0.2 1 2\r\n",
+            b"PROXY TCP4 10.0.0.1 10.0.0.2 1 70000\r\n",
+            b"PROXY TCP4 10.0.0.1 10.0.0.2 1 2",
+            &[b"PROXY ".as_slice(), &[b'x'; 120usize]].concat(),
+        ] {
+            assert!(read_header(&mut &malformed[..]).await.is_err());
+        }
+
+        let mut truncated = V2_SIGNATURE.to_vec();
+        truncated.extend_from_slice(&[0x21u8, 0x11u8, 0u8, 4u8, 10u8, 0u8, 0u8, 1u8]);
+        assert!(read_header(&mut truncated.as_slice()).await.is_err());
+
+        let mut version_1 = V2_SIGNATURE.to_vec();
+        version_1.extend_from_slice(&[0x11u8, 0x00u8, 0u8, 0u8]);
+        assert!(read_header(&mut version_1.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_accept() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+            .await
+            .unwrap();
+        let local = listener.local_addr().unwrap();
+        let trusted = [IpNet::from_str("127.0.0.0/8").unwrap()];
+
+        let mut client = TcpStream::connect(local).await.unwrap();
+        let (mut stream, peer) = listener.accept().await.unwrap();
+        client.write_all(b"PROXY UNKNOWN\r\n").await.unwrap();
+        assert_eq!((peer, local), accept(&mut stream, &trusted).await.unwrap());
+
+        let mut client = TcpStream::connect(local).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        client.write_all(b"PROXY TCP4 10.0.0.1").await.unwrap();
+        drop(client);
+        assert_eq!(ErrorKind::InvalidData, accept(&mut stream, &trusted).await.unwrap_err().kind());
+
+        // nothing is read from untrusted peers
+        let mut client = TcpStream::connect(local).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        client.write_all(b"PROXY UNKNOWN\r\n").await.unwrap();
+        let untrusted = [IpNet::from_str("10.0.0.0/8").unwrap()];
+        assert_eq!(ErrorKind::PermissionDenied, accept(&mut stream, &untrusted).await.unwrap_err().kind());
+        let mut buf = [0u8; 15];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"PROXY UNKNOWN\r\n", &buf);
+    }
+}
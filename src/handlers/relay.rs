@@ -0,0 +1,410 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use libc::{EINVAL, ENOSYS};
+use log::warn;
+use nix::{
+    fcntl::{OFlag, SpliceFFlags, splice},
+    unistd::pipe2,
+};
+use socket2::SockRef;
+use std::{
+    fmt,
+    io::{ErrorKind, Result},
+    net::Shutdown,
+    os::fd::OwnedFd,
+    pin::pin,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::{io::Interest, net::TcpStream, select, time::sleep, try_join};
+
+use crate::utils::structs::Settings;
+
+use super::constants::SPLICE_LEN;
+
+/// Pipe moving data between two sockets inside the kernel
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> Result<Self> {
+        let (read, write) = pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+        Ok(Self { read, write })
+    }
+}
+
+/// Bytes relayed by a TCP session in each direction & when it last relayed any
+struct Traffic {
+    started: Instant,
+    last_active: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Traffic {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_active: AtomicU64::new(0u64),
+            sent: AtomicU64::new(0u64),
+            received: AtomicU64::new(0u64),
+        }
+    }
+
+    /// Counts `n` bytes relayed in the direction of `moved`, marking the session as active now
+    fn record(&self, moved: &AtomicU64, n: usize) {
+        moved.fetch_add(n as u64, Ordering::Relaxed);
+        self.last_active
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.started
+            .elapsed()
+            .saturating_sub(Duration::from_millis(self.last_active.load(Ordering::Relaxed)))
+    }
+
+    /// Bytes sent & received by the client
+    fn totals(&self) -> (u64, u64) {
+        (self.sent.load(Ordering::Relaxed), self.received.load(Ordering::Relaxed))
+    }
+
+    /// Time left before the session expires under `settings`, never if `None`
+    fn expires_in(&self, settings: &Settings) -> Option<Duration> {
+        let idle = settings
+            .idle_timeout
+            .map(|t| t.saturating_sub(self.idle_for()));
+        let lifetime = settings
+            .max_lifetime
+            .map(|t| t.saturating_sub(self.started.elapsed()));
+
+        idle.into_iter().chain(lifetime).min()
+    }
+
+    /// Timeout of `settings` the session has hit, if any
+    fn expired(&self, settings: &Settings) -> Option<Expiry> {
+        match (settings.max_lifetime, settings.idle_timeout) {
+            (Some(t), _) if self.started.elapsed() >= t => Some(Expiry::MaxLifetime(t)),
+            (_, Some(t)) if self.idle_for() >= t => Some(Expiry::Idle(t)),
+            _ => None,
+        }
+    }
+}
+
+/// Timeout closing a TCP session
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Expiry {
+    Idle(Duration),
+    MaxLifetime(Duration),
+}
+
+impl fmt::Display for Expiry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expiry::Idle(t) => write!(f, "after being idle for {}s", t.as_secs()),
+            Expiry::MaxLifetime(t) => write!(f, "after reaching its maximum lifetime of {}s", t.as_secs()),
+        }
+    }
+}
+
+/// Outcome of a relayed TCP session
+#[derive(Debug, Eq, PartialEq)]
+pub(super) struct Relayed {
+    pub(super) sent: u64,
+    pub(super) received: u64,
+    /// Timeout which closed the session, `None` if both sides closed it
+    pub(super) expired: Option<Expiry>,
+}
+
+/// Runs a non-blocking socket operation once the socket is ready for it
+async fn io_ready<R>(stream: &TcpStream, interest: Interest, mut f: impl FnMut() -> Result<R>) -> Result<R> {
+    loop {
+        stream.ready(interest).await?;
+
+        match stream.try_io(interest, &mut f) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            res => return res,
+        }
+    }
+}
+
+/// Copies `src` to `dst` through a user-space buffer of `buffer_size` bytes until EOF, then half-closes `dst`
+async fn copy_buffered(src: &TcpStream, dst: &TcpStream, buffer_size: usize, traffic: &Traffic, moved: &AtomicU64) -> Result<()> {
+    let mut buf = vec![0u8; buffer_size];
+
+    loop {
+        let n = io_ready(src, Interest::READABLE, || src.try_read(&mut buf)).await?;
+        if n == 0usize {
+            SockRef::from(dst).shutdown(Shutdown::Write)?;
+            return Ok(());
+        }
+
+        let mut sent = 0usize;
+        while sent < n {
+            let written = io_ready(dst, Interest::WRITABLE, || dst.try_write(&buf[sent..n])).await?;
+            traffic.record(moved, written);
+            sent += written;
+        }
+    }
+}
+
+/// Moves `src` to `dst` with splice(2) through the pipe until EOF, then half-closes `dst`
+///
+/// * Falls back to [`copy_buffered`] if the sockets can't be spliced before any data is moved
+async fn copy_spliced(src: &TcpStream, dst: &TcpStream, pipe: Pipe, buffer_size: usize, traffic: &Traffic, moved: &AtomicU64) -> Result<()> {
+    let flags = SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_NONBLOCK;
+    let mut spliced = false;
+
+    loop {
+        let res = io_ready(src, Interest::READABLE, || Ok(splice(src, None, &pipe.write, None, SPLICE_LEN, flags)?)).await;
+
+        let n = match res {
+            Ok(n) => n,
+            Err(e) if !spliced && matches!(e.raw_os_error(), Some(EINVAL | ENOSYS)) => {
+                warn!("splice(2) not possible, falling back to buffered copy - {e}");
+                return copy_buffered(src, dst, buffer_size, traffic, moved).await;
+            },
+            Err(e) => return Err(e),
+        };
+
+        if n == 0usize {
+            SockRef::from(dst).shutdown(Shutdown::Write)?;
+            return Ok(());
+        }
+
+        spliced = true;
+        let mut left = n;
+        while left > 0usize {
+            let written = io_ready(dst, Interest::WRITABLE, || Ok(splice(&pipe.read, None, dst, None, left, flags)?)).await?;
+            traffic.record(moved, written);
+            left -= written;
+        }
+    }
+}
+
+/// Relays a TCP session in both directions, with splice(2) if `spliced`, until both sides close or a timeout of `settings` hits
+///
+/// * Like [`tokio::io::copy_bidirectional`], EOF on one side is propagated to the other as a half-close
+/// * Splicing uses a pipe per direction, falling back to a copy through `buffer_size` bytes buffers if the pipes can't be created
+/// * Once idle for the idle timeout or older than the maximum lifetime, both halves of both sides are shut down
+pub(super) async fn relay(client: &TcpStream, upstream: &TcpStream, spliced: bool, settings: &Settings) -> Result<Relayed> {
+    let traffic = Traffic::new();
+    let buffer_size = settings.buffer_size;
+
+    let pipes = match spliced {
+        true => match (Pipe::new(), Pipe::new()) {
+            (Ok(up), Ok(down)) => Some((up, down)),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("Failed to create splice pipes, falling back to buffered copy - {e}");
+                None
+            },
+        },
+        false => None,
+    };
+
+    let mut transfer = pin!(async {
+        match pipes {
+            Some((up, down)) => try_join!(
+                copy_spliced(client, upstream, up, buffer_size, &traffic, &traffic.sent),
+                copy_spliced(upstream, client, down, buffer_size, &traffic, &traffic.received)
+            ),
+            None => try_join!(
+                copy_buffered(client, upstream, buffer_size, &traffic, &traffic.sent),
+                copy_buffered(upstream, client, buffer_size, &traffic, &traffic.received)
+            ),
+        }
+    });
+
+    loop {
+        let expires_in = traffic.expires_in(settings);
+
+        select! {
+            result = &mut transfer => {
+                result?;
+                let (sent, received) = traffic.totals();
+                return Ok(Relayed { sent, received, expired: None });
+            }
+
+            _ = sleep(expires_in.unwrap_or_default()), if expires_in.is_some() => {
+                if let Some(expiry) = traffic.expired(settings) {
+                    // the other side may already be gone
+                    let _ = SockRef::from(client).shutdown(Shutdown::Both);
+                    let _ = SockRef::from(upstream).shutdown(Shutdown::Both);
+
+                    let (sent, received) = traffic.totals();
+                    return Ok(Relayed { sent, received, expired: Some(expiry) });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::net::Ipv4Addr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time::timeout,
+    };
+
+    use crate::utils::structs::fixtures::default_settings;
+
+    use super::*;
+
+    /// Client & upstream ends of a relayed session, with the relay's own ends
+    async fn session() -> ((TcpStream, TcpStream), (TcpStream, TcpStream)) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0u16))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (relay_client, _) = listener.accept().await.unwrap();
+        let relay_upstream = TcpStream::connect(addr).await.unwrap();
+        let (upstream, _) = listener.accept().await.unwrap();
+
+        ((client, upstream), (relay_client, relay_upstream))
+    }
+
+    fn settings(idle_timeout: Option<Duration>, max_lifetime: Option<Duration>) -> Settings {
+        Settings {
+            idle_timeout,
+            max_lifetime,
+            ..default_settings()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        for spliced in [true, false] {
+            let ((mut client, mut upstream), (relay_client, relay_upstream)) = session().await;
+            let relay = tokio::spawn(async move { relay(&relay_client, &relay_upstream, spliced, &settings(None, None)).await });
+
+            let request = vec![7u8; 200_000usize];
+            client.write_all(&request).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            let mut received = Vec::new();
+            upstream.read_to_end(&mut received).await.unwrap();
+            assert_eq!(request, received);
+
+            // the other direction keeps flowing after the half-close
+            upstream.write_all(b"response").await.unwrap();
+            drop(upstream);
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert_eq!(b"response".to_vec(), response);
+
+            let expected = Relayed {
+                sent: 200_000u64,
+                received: 8u64,
+                expired: None,
+            };
+            assert_eq!(expected, relay.await.unwrap().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_half_close() {
+        for spliced in [true, false] {
+            let ((mut client, mut upstream), (relay_client, relay_upstream)) = session().await;
+            let mut relay = tokio::spawn(async move { relay(&relay_client, &relay_upstream, spliced, &settings(None, None)).await });
+
+            client.write_all(b"request").await.unwrap();
+            client.shutdown().await.unwrap();
+
+            // the upstream reads the request then EOF
+            let mut request = Vec::new();
+            upstream.read_to_end(&mut request).await.unwrap();
+            assert_eq!(b"request".to_vec(), request);
+
+            // replies still reach the client, the session staying open
+            let mut buf = [0u8; 5usize];
+            for reply in [b"reply", b"again"] {
+                upstream.write_all(reply).await.unwrap();
+                client.read_exact(&mut buf).await.unwrap();
+                assert_eq!(reply, &buf);
+                assert!(
+                    timeout(Duration::from_millis(50u64), &mut relay)
+                        .await
+                        .is_err()
+                );
+            }
+
+            // it closes once the upstream is done too
+            upstream.shutdown().await.unwrap();
+            assert_eq!(0usize, client.read(&mut buf).await.unwrap());
+
+            let relayed = timeout(Duration::from_secs(1u64), relay)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            let expected = Relayed {
+                sent: 7u64,
+                received: 10u64,
+                expired: None,
+            };
+            assert_eq!(expected, relayed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_expired() {
+        let idle = settings(Some(Duration::from_millis(100u64)), None);
+        let lifetime = settings(None, Some(Duration::from_millis(150u64)));
+
+        for (settings, expiry) in [
+            (idle, Expiry::Idle(Duration::from_millis(100u64))),
+            (lifetime, Expiry::MaxLifetime(Duration::from_millis(150u64))),
+        ] {
+            let ((mut client, mut upstream), (relay_client, relay_upstream)) = session().await;
+            let relay = tokio::spawn(async move { relay(&relay_client, &relay_upstream, false, &settings).await });
+
+            // activity keeps the idle timeout from hitting, not the maximum lifetime
+            let mut buf = [0u8; 4usize];
+            for _ in 0..3 {
+                client.write_all(b"ping").await.unwrap();
+                upstream.read_exact(&mut buf).await.unwrap();
+                sleep(Duration::from_millis(40u64)).await;
+            }
+
+            let relayed = timeout(Duration::from_secs(1u64), relay)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(12u64, relayed.sent);
+            assert_eq!(Some(expiry), relayed.expired);
+
+            // both sides see the session closed
+            assert_eq!(0usize, client.read(&mut buf).await.unwrap());
+            assert_eq!(0usize, upstream.read(&mut buf).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_buffered() {
+        let ((mut client, mut upstream), (relay_client, relay_upstream)) = session().await;
+        let relay = tokio::spawn(async move {
+            let traffic = Traffic::new();
+            copy_buffered(&relay_client, &relay_upstream, 16usize, &traffic, &traffic.sent)
+                .await
+                .map(|_| traffic.totals())
+        });
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        upstream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(b"request".to_vec(), received);
+        assert_eq!((7u64, 0u64), relay.await.unwrap().unwrap());
+    }
+}
@@ -0,0 +1,330 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use arc_swap::ArcSwap;
+use hickory_resolver::{
+    TokioResolver,
+    config::{LookupIpStrategy, NameServerConfigGroup, ResolverConfig},
+    name_server::TokioConnectionProvider,
+};
+use log::{error, info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Error, ErrorKind, Result},
+    mem,
+    net::IpAddr,
+    sync::Arc,
+};
+use tokio::{
+    select,
+    sync::watch::Receiver,
+    task::JoinSet,
+    time::{Instant, sleep_until, timeout},
+};
+
+use crate::utils::structs::{Actions, RuntimeConfigs};
+
+use super::constants::{MAX_RESOLVE_INTERVAL, MIN_RESOLVE_INTERVAL, PENDING_RESOLVE_TIMEOUT};
+
+/// Last resolution of an upstream hostname
+struct NameState {
+    ips: Vec<IpAddr>,
+    due: Instant,
+    running: bool,
+}
+
+/// Creates a resolver querying the nameservers, else the system ones
+///
+/// * Both IPv4 & IPv6 addresses are looked up
+fn build_resolver(nameservers: &[IpAddr]) -> Result<TokioResolver> {
+    let mut builder = match nameservers.is_empty() {
+        true => TokioResolver::builder_tokio().map_err(|e| Error::other(format!("Failed to read system resolver configuration - {e}")))?,
+        false => TokioResolver::builder_with_config(
+            ResolverConfig::from_parts(None, Vec::new(), NameServerConfigGroup::from_ips_clear(nameservers, 53u16, true)),
+            TokioConnectionProvider::default(),
+        ),
+    };
+
+    builder.options_mut().ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    Ok(builder.build())
+}
+
+/// Replaces the hostnames with those of the configuration, feeding the addresses already known to its pools
+///
+/// * Names its pools were already given addresses for are resolved again after the minimum interval
+fn refresh(names: &mut HashMap<String, NameState>, config: &RuntimeConfigs) {
+    let now = Instant::now();
+    let mut old = mem::take(names);
+
+    for pool in config.pools() {
+        for name in pool.hostnames() {
+            let state = match names.remove(name).or_else(|| old.remove(name)) {
+                Some(s) => s,
+                None => match pool.resolved(name) {
+                    Some(ips) => NameState {
+                        ips,
+                        due: now + MIN_RESOLVE_INTERVAL,
+                        running: false,
+                    },
+                    None => NameState {
+                        ips: Vec::new(),
+                        due: now,
+                        running: false,
+                    },
+                },
+            };
+
+            if !state.ips.is_empty() {
+                pool.set_resolved(name, &state.ips);
+            }
+
+            names.insert(name.to_string(), state);
+        }
+    }
+}
+
+/// Next resolution time of a name, following its TTL within bounds
+fn next_due(now: Instant, valid_until: Instant) -> Instant {
+    valid_until.clamp(now + MIN_RESOLVE_INTERVAL, now + MAX_RESOLVE_INTERVAL)
+}
+
+async fn resolve(resolver: TokioResolver, name: String) -> (String, Result<(Vec<IpAddr>, Instant)>) {
+    let res = match resolver.lookup_ip(name.as_str()).await {
+        Ok(lookup) => {
+            let mut ips = lookup.iter().collect::<Vec<_>>();
+            ips.sort();
+            ips.dedup();
+            Ok((ips, Instant::from_std(lookup.valid_until())))
+        },
+        Err(e) => Err(Error::other(e)),
+    };
+
+    (name, res)
+}
+
+/// Resolves the hostnames not resolved yet, so their pools have upstreams once the configuration is in use
+///
+/// * Failures are only logged, the resolver task retrying those names
+/// * Names still unresolved after a short timeout are left to the resolver task too, so a slow nameserver can't hold up signals
+pub(crate) async fn resolve_pending(config: &RuntimeConfigs) -> Result<()> {
+    let pending = config
+        .pools()
+        .into_iter()
+        .flat_map(|p| p.hostnames().filter(|n| p.resolved(n).is_none()))
+        .map(String::from)
+        .collect::<HashSet<_>>();
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let dns = build_resolver(&config.nameservers)?;
+    let mut tasks = JoinSet::new();
+    for name in pending {
+        tasks.spawn(resolve(dns.clone(), name));
+    }
+
+    let resolved = async {
+        while let Some(res) = tasks.join_next().await {
+            match res {
+                Ok((name, Ok((ips, _)))) => {
+                    info!("Upstream {name} resolved to {ips:?}");
+                    config
+                        .pools()
+                        .iter()
+                        .for_each(|p| p.set_resolved(&name, &ips));
+                },
+                Ok((name, Err(e))) => warn!("Failed to resolve upstream {name} - {e}"),
+                Err(e) => error!("Resolver task join error: {e}"),
+            };
+        }
+    };
+
+    // the lookups still running are aborted along with the tasks
+    timeout(PENDING_RESOLVE_TIMEOUT, resolved)
+        .await
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::TimedOut,
+                format!("{} names still unresolved after {}s", tasks.len(), PENDING_RESOLVE_TIMEOUT.as_secs()),
+            )
+        })
+}
+
+/// Resolver function
+///
+/// Resolves the upstream hostnames, then re-resolves each one once its records expire.
+/// Pools get every address a hostname resolves to, the last ones being kept while resolution fails.
+pub(crate) async fn resolver(mut rx: Receiver<Actions>, current_config: Arc<ArcSwap<RuntimeConfigs>>) -> Result<()> {
+    info!("Resolver starting...");
+
+    let action = rx.borrow().clone();
+    match action {
+        Actions::STOP(s) => {
+            info!("Resolver shut down before starting as {s} failed");
+            return Ok(());
+        },
+        Actions::PANICKED => {
+            info!("Resolver shut down before starting as someone panicked");
+            return Ok(());
+        },
+        Actions::KILL | Actions::SHUTDOWN => {
+            info!("Resolver shut down before starting");
+            return Ok(());
+        },
+        _ => { /* RELOAD or INIT has no effect now */ },
+    };
+
+    let (mut nameservers, mut dns) = {
+        let config = current_config.load();
+        (config.nameservers.clone(), build_resolver(&config.nameservers)?)
+    };
+
+    let mut names = HashMap::new();
+    refresh(&mut names, &current_config.load());
+
+    let mut tasks = JoinSet::new();
+
+    'resolver_loop: loop {
+        let next = names.values().filter(|n| !n.running).map(|n| n.due).min();
+
+        select! {
+            sig = rx.changed() => {
+                match sig {
+                    Ok(_) => {
+                        let action = rx.borrow().clone();
+                        match action {
+                            Actions::RELOAD(_) => {
+                                info!("RELOAD signal received by Resolver...");
+
+                                let config = current_config.load();
+                                if config.nameservers != nameservers {
+                                    match build_resolver(&config.nameservers) {
+                                        Ok(r) => {
+                                            dns = r;
+                                            nameservers = config.nameservers.clone();
+                                            names.values_mut().for_each(|n| n.due = Instant::now());
+                                        },
+                                        Err(e) => error!("{e}")
+                                    };
+                                }
+
+                                refresh(&mut names, &config);
+                                continue 'resolver_loop;
+                            },
+                            Actions::STOP(s) => {
+                                info!("{s} failed...Shutting down Resolver...");
+                                break 'resolver_loop;
+                            },
+                            Actions::KILL | Actions::PANICKED | Actions::SHUTDOWN => {
+                                info!("Shutting down Resolver...");
+                                break 'resolver_loop;
+                            },
+                            Actions::INIT => {/* INIT will not come here */}
+                        }
+                    },
+                    Err(_) => {
+                        error!("Signal channel closed...Shutting down Resolver...");
+                        break 'resolver_loop;
+                    }
+                };
+            }
+
+            _ = sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                let now = Instant::now();
+
+                for (name, state) in names.iter_mut().filter(|(_, n)| !n.running && n.due <= now) {
+                    state.running = true;
+                    tasks.spawn(resolve(dns.clone(), name.clone()));
+                }
+            }
+
+            Some(res) = tasks.join_next(), if !tasks.is_empty() => {
+                let (name, res) = match res {
+                    Ok(r) => r,
+                    Err(e) => {
+                        error!("Resolver task join error: {e}");
+                        continue 'resolver_loop;
+                    }
+                };
+
+                // names removed by a reload meanwhile are ignored
+                let Some(state) = names.get_mut(&name) else {
+                    continue 'resolver_loop;
+                };
+
+                let now = Instant::now();
+                state.running = false;
+
+                match res {
+                    Ok((ips, valid_until)) => {
+                        state.due = next_due(now, valid_until);
+
+                        if ips != state.ips {
+                            info!("Upstream {name} resolved to {ips:?}");
+                            state.ips = ips;
+                            current_config.load().pools().iter().for_each(|p| p.set_resolved(&name, &state.ips));
+                        }
+                    },
+                    Err(e) => {
+                        state.due = now + MIN_RESOLVE_INTERVAL;
+                        warn!("Failed to resolve upstream {name}, keeping {:?} - {e}", state.ips);
+                    }
+                };
+            }
+        }
+    }
+
+    tasks.abort_all();
+
+    info!("Resolver shut down");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    use crate::utils::structs::fixtures::runtime_configs;
+
+    use super::*;
+
+    #[test]
+    fn test_next_due() {
+        let now = Instant::now();
+        assert_eq!(now + MIN_RESOLVE_INTERVAL, next_due(now, now));
+        assert_eq!(now + MAX_RESOLVE_INTERVAL, next_due(now, now + MAX_RESOLVE_INTERVAL * 2u32));
+
+        let ttl = MIN_RESOLVE_INTERVAL + Duration::from_secs(1u64);
+        assert_eq!(now + ttl, next_due(now, now + ttl));
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let dns = build_resolver(&[IpAddr::from([127u8, 0u8, 0u8, 1u8])]).unwrap();
+
+        let (name, res) = resolve(dns, "10.0.0.1".into()).await;
+        assert_eq!("10.0.0.1", name);
+        assert_eq!(vec![IpAddr::from([10u8, 0u8, 0u8, 1u8])], res.unwrap().0);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_pending() {
+        // a nameserver which never answers
+        let _silent = UdpSocket::bind("127.0.0.53:53").await.unwrap();
+        let config = runtime_configs(
+            r#"{
+                "tcp_port": 8080,
+                "nameservers": ["127.0.0.53"],
+                "tcp": [{ "upstreams": [{ "host": "web.lan", "port": 80 }], "orig_port": 80 }]
+            }"#,
+        );
+
+        let start = Instant::now();
+        let err = resolve_pending(&config).await.unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+        assert!(start.elapsed() < PENDING_RESOLVE_TIMEOUT + Duration::from_secs(1u64));
+        assert_eq!(None, config.pools()[0].resolved("web.lan"));
+    }
+}
@@ -5,6 +5,7 @@ use log::{error, info, warn};
 use sd_notify::{NotifyState, notify};
 use std::{
     io::{Error, ErrorKind, Result},
+    net::SocketAddr,
     path::PathBuf,
     sync::Arc,
 };
@@ -12,16 +13,43 @@ use tokio::{
     select,
     signal::unix::{SignalKind, signal},
     sync::watch::{Receiver, Sender},
+    time::{Instant, sleep_until},
 };
 
 use crate::utils::{
-    structs::{Actions, RuntimeConfigs},
-    utils::read_config,
+    structs::{Actions, RuntimeConfigs, bindings},
+    utils::{check_return_routing, read_config},
 };
 
-use super::constants::LISTEN_IP;
+use super::{constants::STATUS_INTERVAL, resolver::resolve_pending};
+
+/// Systemd status message with the addresses the forwarders listen at & the counters of the limits
+pub(crate) fn listen_status(config: &RuntimeConfigs) -> String {
+    let join = |addrs: Vec<SocketAddr>| {
+        addrs
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let limits = config
+        .limits()
+        .into_iter()
+        .map(|(name, limit)| format!("{name} limit {limit}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "Configured to listen at TCP [{}] & UDP [{}] - {limits}",
+        join(bindings(&config.tcp_listeners).0),
+        join(bindings(&config.udp_listeners).0)
+    )
+}
 
 /// Handles signals (SIGINT, SIGTERM, SIGQUIT & SIGHUP)
+///
+/// * The systemd status is refreshed every few seconds, the limit counters being reported there
 pub(crate) async fn signal_handler(
     tx: Sender<Actions>, mut rx: Receiver<Actions>, config_path: &PathBuf, current_config: Arc<ArcSwap<RuntimeConfigs>>,
 ) -> Result<()> {
@@ -72,6 +100,8 @@ pub(crate) async fn signal_handler(
         },
     };
 
+    let mut status_due = Instant::now() + STATUS_INTERVAL;
+
     'signal_handler_loop: loop {
         select! {
             sig = rx.changed() => {
@@ -133,40 +163,75 @@ pub(crate) async fn signal_handler(
                     warn!("Systemd RELOADING & MONOTONIC_USEC notify failed - {e}");
                 }
 
-                match read_config(config_path).await {
-                    Ok(new_file_config) => {
-                        let new_config = RuntimeConfigs::from(&new_file_config);
-
-                        let (needs_update, port_changed) = {
+                match read_config(config_path).await.map(|c| RuntimeConfigs::try_from(&c)) {
+                    Ok(Ok(mut new_config)) => {
+                        let (needs_update, listen_changed) = {
                             let old_cfg = current_config.load();
-                            (**old_cfg != new_config, old_cfg.port != new_config.port)
+                            (
+                                **old_cfg != new_config,
+                                bindings(&old_cfg.tcp_listeners).0 != bindings(&new_config.tcp_listeners).0
+                                    || bindings(&old_cfg.udp_listeners).0 != bindings(&new_config.udp_listeners).0,
+                            )
                         };
 
+                        let workers = current_config.load().workers;
+                        if new_config.workers != workers {
+                            warn!("Worker count change from {workers} to {} takes effect after a restart", new_config.workers);
+                        }
+
+                        // only ever warns, so signals aren't held up by the `ip` commands it runs
+                        let spoofing_addrs = new_config.spoofing_addrs();
+                        tokio::spawn(async move {
+                            if let Err(e) = check_return_routing(&spoofing_addrs).await {
+                                warn!("Upstream replies to spoofed-source connections may not reach the proxy - {e}");
+                            }
+                        });
+
                         if needs_update {
+                            // compared first, as the current limits take the new maximums
+                            new_config.carry_limits(&current_config.load());
+                            new_config.carry_resolved(&current_config.load());
+                            if let Err(e) = resolve_pending(&new_config).await {
+                                warn!("Upstream hostnames left to the resolver - {e}");
+                            }
+
                             current_config.store(Arc::new(new_config));
-                            tx.send_replace(Actions::RELOAD(port_changed));
+                            tx.send_replace(Actions::RELOAD(listen_changed));
                         } else {
                             info!("Configuration unchanged");
                         }
                     },
+                    Ok(Err(e)) => error!("Invalid configuration - {e}"),
                     Err(e) => error!("{e}")
                 };
 
+                for (name, limit) in current_config.load().limits() {
+                    info!("{name} limit - {limit}");
+                }
+
                 if let Err(e) = notify(false, &[NotifyState::Ready]) {
                     warn!("Systemd READY notify failed after reload - {e}");
                 }
 
                 if let Err(e) = notify(
                     false,
-                    &[NotifyState::Status(
-                        &format!("Configured to listen at {}:{}", LISTEN_IP, current_config.load().port)
-                    )]
+                    &[NotifyState::Status(&listen_status(&current_config.load()))]
                 ) {
                     warn!("Systemd STATUS notify failed - {e}");
                 }
 
                 continue 'signal_handler_loop;
             },
+
+            _ = sleep_until(status_due) => {
+                status_due = Instant::now() + STATUS_INTERVAL;
+
+                if let Err(e) = notify(false, &[NotifyState::Status(&listen_status(&current_config.load()))]) {
+                    warn!("Systemd STATUS notify failed - {e}");
+                }
+
+                continue 'signal_handler_loop;
+            },
         }
     }
 
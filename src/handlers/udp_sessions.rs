@@ -0,0 +1,609 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use arc_swap::ArcSwap;
+use log::{error, info, warn};
+use socket2::SockRef;
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, IoSlice, Result},
+    mem,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::{io::Interest, net::UdpSocket, select, sync::watch::Receiver, time::sleep};
+
+use crate::utils::{
+    limits::ConnPermit,
+    structs::{Actions, ForwarderMap, RuntimeConfigs, Settings},
+    upstreams::Lease,
+};
+
+use super::{
+    constants::{REPLY_SOCKET_CACHE_SIZE, UDP_BATCH},
+    helpers::{Egress, create_udp_reply_socket, create_udp_upstream_socket, recv_whole, sendmmsg_to, try_recv_whole},
+};
+
+/// UDP session key - client address & original destination
+pub(super) type SessionKey = (SocketAddr, SocketAddr);
+
+/// No client datagram is awaiting a reply
+const NOT_AWAITING: u64 = u64::MAX;
+
+/// A NAT-like UDP session, holding the upstream socket shared by all datagrams of a flow
+///
+/// * The session task creates the upstream socket, client datagrams being queued until then
+pub(super) struct UdpSession {
+    upstream_addr: SocketAddr,
+    upstream: OnceLock<UdpSocket>,
+    /// Client datagrams received before the upstream socket was created, `None` once it is
+    queued: Mutex<Option<Vec<Vec<u8>>>>,
+    /// Name of the listener which intercepted the session
+    listener: String,
+    /// Settings of the rule matched when the session was created
+    settings: Settings,
+    /// PROXY protocol header prepended to every client datagram sent upstream
+    proxy_header: Option<Vec<u8>>,
+    created: Instant,
+    last_active: AtomicU64,
+    /// When the oldest client datagram not replied to yet was sent upstream
+    awaiting_since: AtomicU64,
+    /// Keeps the upstream counted as active for the session lifetime
+    _lease: Option<Lease>,
+}
+
+impl UdpSession {
+    pub(super) fn new(upstream_addr: SocketAddr, lease: Option<Lease>, listener: String, settings: Settings, proxy_header: Option<Vec<u8>>) -> Self {
+        Self {
+            upstream_addr,
+            upstream: OnceLock::new(),
+            queued: Mutex::new(Some(Vec::new())),
+            listener,
+            settings,
+            proxy_header,
+            created: Instant::now(),
+            last_active: AtomicU64::new(0u64),
+            awaiting_since: AtomicU64::new(NOT_AWAITING),
+            _lease: lease,
+        }
+    }
+
+    fn elapsed_millis(&self) -> u64 {
+        self.created.elapsed().as_millis() as u64
+    }
+
+    /// Sends a client datagram upstream without waiting, behind the PROXY protocol header if any
+    ///
+    /// * Until the upstream socket is created, up to a batch of datagrams is queued instead
+    /// * A refusal of an earlier datagram reported by the send is cleared by it, so the datagram is sent again
+    pub(super) fn try_send(&self, payload: &[u8]) -> Result<usize> {
+        let upstream = match self.upstream.get() {
+            Some(u) => u,
+            None => {
+                let mut queued = self.queued.lock().unwrap_or_else(|e| e.into_inner());
+                match (queued.as_mut(), self.upstream.get()) {
+                    (_, Some(u)) => u,
+                    (Some(q), None) if q.len() < UDP_BATCH => {
+                        q.push(payload.to_vec());
+                        return Ok(payload.len());
+                    },
+                    _ => return Err(Error::new(ErrorKind::WouldBlock, "Upstream socket not created yet & queue full")),
+                }
+            },
+        };
+
+        let try_send = || match &self.proxy_header {
+            Some(header) => upstream.try_io(Interest::WRITABLE, || {
+                SockRef::from(upstream).send_vectored(&[IoSlice::new(header), IoSlice::new(payload)])
+            }),
+            None => upstream.try_send(payload),
+        };
+
+        match try_send() {
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => try_send(),
+            res => res,
+        }
+    }
+
+    /// Sends a client datagram upstream, waiting for room in the socket buffer
+    async fn send(&self, upstream: &UdpSocket, payload: &[u8]) -> Result<usize> {
+        let send = async || match &self.proxy_header {
+            Some(header) => {
+                upstream
+                    .async_io(Interest::WRITABLE, || {
+                        SockRef::from(upstream).send_vectored(&[IoSlice::new(header), IoSlice::new(payload)])
+                    })
+                    .await
+            },
+            None => upstream.send(payload).await,
+        };
+
+        // as in try_send, a refusal of an earlier datagram leaves this one unsent
+        match send().await {
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => send().await,
+            res => res,
+        }
+    }
+
+    /// Sends the queued client datagrams in order through the new upstream socket, which then takes the next ones right away
+    pub(super) async fn start(&self, socket: UdpSocket) -> &UdpSocket {
+        loop {
+            let queued = {
+                let mut queued = self.queued.lock().unwrap_or_else(|e| e.into_inner());
+                match queued.as_mut().map(mem::take).filter(|q| !q.is_empty()) {
+                    Some(q) => q,
+                    None => {
+                        // set along with the queue being dropped, so no datagram is left behind in it
+                        *queued = None;
+                        return self.upstream.get_or_init(|| socket);
+                    },
+                }
+            };
+
+            for datagram in queued {
+                if let Err(e) = self.send(&socket, &datagram).await {
+                    error!("Failed to send UDP datagram to upstream {} - {e}", self.upstream_addr);
+                }
+            }
+        }
+    }
+
+    /// Marks the session as active now
+    pub(super) fn touch(&self) {
+        self.last_active
+            .store(self.elapsed_millis(), Ordering::Relaxed);
+    }
+
+    /// Marks a client datagram as sent upstream, awaiting a reply unless one already is
+    pub(super) fn requested(&self) {
+        let _ = self
+            .awaiting_since
+            .compare_exchange(NOT_AWAITING, self.elapsed_millis(), Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// Marks the client datagrams sent upstream as replied to
+    pub(super) fn replied(&self) {
+        self.awaiting_since.store(NOT_AWAITING, Ordering::Relaxed);
+    }
+
+    /// Time elapsed since the oldest client datagram not replied to yet was sent upstream
+    pub(super) fn awaiting_reply_for(&self) -> Option<Duration> {
+        match self.awaiting_since.load(Ordering::Relaxed) {
+            NOT_AWAITING => None,
+            since => Some(
+                self.created
+                    .elapsed()
+                    .saturating_sub(Duration::from_millis(since)),
+            ),
+        }
+    }
+
+    /// Time left before the session expires under `settings`, never if `None`
+    pub(super) fn expires_in(&self, settings: &Settings) -> Option<Duration> {
+        let idle = settings
+            .idle_timeout
+            .map(|t| t.saturating_sub(self.idle_for()));
+        let reply = settings
+            .reply_timeout
+            .zip(self.awaiting_reply_for())
+            .map(|(t, waited)| t.saturating_sub(waited));
+
+        idle.into_iter().chain(reply).min()
+    }
+
+    /// Time elapsed since the session was last active
+    pub(super) fn idle_for(&self) -> Duration {
+        self.created
+            .elapsed()
+            .saturating_sub(Duration::from_millis(self.last_active.load(Ordering::Relaxed)))
+    }
+}
+
+/// UDP session table
+#[derive(Default)]
+pub(super) struct UdpSessions(Mutex<HashMap<SessionKey, Arc<UdpSession>>>);
+
+impl UdpSessions {
+    pub(super) fn get(&self, key: &SessionKey) -> Option<Arc<UdpSession>> {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(key)
+            .cloned()
+    }
+
+    pub(super) fn insert(&self, key: SessionKey, session: Arc<UdpSession>) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key, session);
+    }
+
+    pub(super) fn remove(&self, key: &SessionKey) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).remove(key);
+    }
+}
+
+/// Transparent reply sockets keyed by original destination, bounded with LRU eviction
+///
+/// * Sessions sharing an original destination send replies from the same socket
+/// * An evicted socket is closed once the last session using it ends
+pub(crate) struct ReplySockets {
+    capacity: usize,
+    create: fn(SocketAddr) -> Result<UdpSocket>,
+    /// Sockets with the tick they were last used at
+    sockets: Mutex<HashMap<SocketAddr, (Arc<UdpSocket>, u64)>>,
+    tick: AtomicU64,
+}
+
+impl ReplySockets {
+    pub(super) fn new(capacity: usize, create: fn(SocketAddr) -> Result<UdpSocket>) -> Self {
+        Self {
+            capacity,
+            create,
+            sockets: Mutex::new(HashMap::new()),
+            tick: AtomicU64::new(0u64),
+        }
+    }
+
+    /// Reply socket bound to the original destination, created if not cached
+    pub(super) fn get(&self, orig_dst: SocketAddr) -> Result<Arc<UdpSocket>> {
+        let tick = self.tick.fetch_add(1u64, Ordering::Relaxed);
+        let mut sockets = self.sockets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some((socket, used)) = sockets.get_mut(&orig_dst) {
+            *used = tick;
+            return Ok(socket.clone());
+        }
+
+        let socket = Arc::new((self.create)(orig_dst)?);
+
+        if sockets.len() >= self.capacity
+            && let Some(lru) = sockets
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(addr, _)| *addr)
+        {
+            sockets.remove(&lru);
+        }
+
+        sockets.insert(orig_dst, (socket.clone(), tick));
+        Ok(socket)
+    }
+}
+
+impl Default for ReplySockets {
+    fn default() -> Self {
+        Self::new(REPLY_SOCKET_CACHE_SIZE, create_udp_reply_socket)
+    }
+}
+
+/// Relays every upstream reply of a session back to the client in batches with sendmmsg(2) until the session expires or the forwarder stops
+///
+/// * The upstream socket is created first, so the forwarder isn't held up by it
+/// * Refusals of the upstream (ICMP port unreachable) are only logged, other receive errors ending the session
+/// * The session expires once idle for the idle timeout of its rule, or once a client datagram has gone without reply for the reply timeout
+/// * The settings of its rule are looked up again on reload
+pub(super) async fn udp_session(
+    key: SessionKey, session: Arc<UdpSession>, sessions: Arc<UdpSessions>, reply_sockets: Arc<ReplySockets>, mut rx: Receiver<Actions>,
+    current_config: Arc<ArcSwap<RuntimeConfigs>>, _permit: ConnPermit,
+) {
+    let (src, orig_dst) = key;
+
+    let reply_socket = match reply_sockets.get(orig_dst) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to create UDP reply socket bound to original destination {orig_dst} - {e}");
+            sessions.remove(&key);
+            return;
+        },
+    };
+
+    let upstream = match create_udp_upstream_socket(&session.upstream_addr, &Egress::new(&session.settings, &src)).await {
+        Ok(s) => session.start(s).await,
+        Err(e) => {
+            error!("Failed to create and connect upstream UDP socket for {} - {e}", session.upstream_addr);
+            sessions.remove(&key);
+            return;
+        },
+    };
+
+    // one buffer, more being added only when a burst of replies is queued
+    let mut settings = session.settings;
+    let mut replies = vec![vec![0u8; settings.buffer_size]];
+
+    'udp_session_loop: loop {
+        let expires_in = session.expires_in(&settings);
+
+        select! {
+            sig = rx.changed() => {
+                match sig {
+                    Ok(_) => {
+                        let action = rx.borrow().clone();
+                        match action {
+                            Actions::RELOAD(_) => {
+                                let route_settings = current_config
+                                    .load()
+                                    .udp_listeners
+                                    .iter()
+                                    .find(|l| l.name == session.listener)
+                                    .and_then(|l| l.map.lookup(&orig_dst).map(|r| r.settings));
+
+                                if let Some(s) = route_settings {
+                                    if s.buffer_size != settings.buffer_size {
+                                        replies = vec![vec![0u8; s.buffer_size]];
+                                    }
+                                    settings = s;
+                                }
+
+                                continue 'udp_session_loop;
+                            },
+                            _ => break 'udp_session_loop,
+                        }
+                    },
+                    Err(_) => break 'udp_session_loop,
+                };
+            }
+
+            result = recv_whole(upstream, &mut replies[0]) => {
+                match result {
+                    Ok(reply_len) => {
+                        session.touch();
+                        session.replied();
+
+                        // replies already queued are sent back along with this one
+                        let mut lens = vec![reply_len];
+                        while lens.len() < UDP_BATCH {
+                            if lens.len() == replies.len() {
+                                if upstream.try_peek_sender().is_err() {
+                                    break;
+                                }
+                                replies.push(vec![0u8; settings.buffer_size]);
+                            }
+
+                            match try_recv_whole(upstream, &mut replies[lens.len()]) {
+                                Ok(len) => lens.push(len),
+                                Err(e) if e.kind() == ErrorKind::InvalidData => continue,
+                                Err(_) => break,
+                            };
+                        }
+
+                        let datagrams = replies.iter().zip(&lens).map(|(r, len)| &r[..*len]).collect::<Vec<_>>();
+                        if let Err(e) = sendmmsg_to(&reply_socket, src, &datagrams).await {
+                            error!("Failed to forward UDP reply back to client {src} - {e}");
+                        }
+                    },
+                    // an ICMP port unreachable for an earlier datagram, the upstream may come back
+                    Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                        warn!("UDP upstream {} refused a datagram of session {src} -> {orig_dst}", session.upstream_addr);
+                    },
+                    Err(e) => {
+                        error!("Failed to receive UDP datagram from upstream for session {src} -> {orig_dst} - {e}");
+                        break 'udp_session_loop;
+                    }
+                };
+            }
+
+            _ = sleep(expires_in.unwrap_or_default()), if expires_in.is_some() => {
+                if session.expires_in(&settings) == Some(Duration::ZERO) {
+                    match session.awaiting_reply_for() {
+                        Some(waited) if settings.reply_timeout.is_some_and(|t| waited >= t) => {
+                            info!("UDP session {src} -> {orig_dst} expired after waiting {}s for a reply", waited.as_secs());
+                        },
+                        _ => info!("UDP session {src} -> {orig_dst} expired after being idle for {}s", session.idle_for().as_secs()),
+                    };
+                    break 'udp_session_loop;
+                }
+            }
+        }
+    }
+
+    sessions.remove(&key);
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::net::Ipv4Addr;
+    use tokio::{sync::watch, time::timeout};
+
+    use crate::utils::{
+        limits::ConnLimit,
+        structs::fixtures::{default_settings, runtime_configs},
+    };
+
+    use super::*;
+
+    fn upstream_addr() -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::new(192u8, 168u8, 1u8, 100u8).into(), 53u16)
+    }
+
+    fn settings(reply_timeout: Option<Duration>) -> Settings {
+        Settings {
+            idle_timeout: Some(Duration::from_secs(30u64)),
+            reply_timeout,
+            buffer_size: 1500usize,
+            ..default_settings()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_UdpSession_idle_for() {
+        let session = UdpSession::new(upstream_addr(), None, "lan".into(), settings(None), None);
+
+        sleep(Duration::from_millis(20u64)).await;
+        assert!(session.idle_for() >= Duration::from_millis(20u64));
+
+        session.touch();
+        assert!(session.idle_for() < Duration::from_millis(20u64));
+    }
+
+    #[tokio::test]
+    async fn test_UdpSession_try_send() {
+        let upstream = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let session = UdpSession::new(upstream_addr, None, "lan".into(), settings(None), Some(b"header:".to_vec()));
+
+        // queued until the upstream socket is created
+        assert_eq!(5usize, session.try_send(b"hello").unwrap());
+        for _ in 1..UDP_BATCH {
+            session.try_send(b"again").unwrap();
+        }
+        assert_eq!(ErrorKind::WouldBlock, session.try_send(b"dropped").unwrap_err().kind());
+        assert!(session.upstream.get().is_none());
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+        socket.connect(upstream_addr).await.unwrap();
+        session.start(socket).await.writable().await.unwrap();
+        assert_eq!(12usize, session.try_send(b"world").unwrap());
+
+        // in the order they were received
+        let mut buf = [0u8; 32];
+        let len = upstream.recv(&mut buf).await.unwrap();
+        assert_eq!(b"header:hello", &buf[..len]);
+        for _ in 1..UDP_BATCH {
+            let len = upstream.recv(&mut buf).await.unwrap();
+            assert_eq!(b"header:again", &buf[..len]);
+        }
+        let len = upstream.recv(&mut buf).await.unwrap();
+        assert_eq!(b"header:world", &buf[..len]);
+    }
+
+    #[tokio::test]
+    async fn test_UdpSession_expires_in() {
+        let session = UdpSession::new(upstream_addr(), None, "lan".into(), settings(None), None);
+        let reply_timeout = settings(Some(Duration::from_millis(50u64)));
+
+        assert!(session.awaiting_reply_for().is_none());
+        assert!(session.expires_in(&reply_timeout).unwrap() > Duration::from_secs(29u64));
+
+        session.requested();
+        sleep(Duration::from_millis(20u64)).await;
+
+        // a later datagram doesn't restart the wait
+        session.requested();
+        assert!(session.awaiting_reply_for().unwrap() >= Duration::from_millis(20u64));
+        assert!(session.expires_in(&reply_timeout).unwrap() <= Duration::from_millis(30u64));
+        assert!(session.expires_in(&settings(None)).unwrap() > Duration::from_secs(29u64));
+
+        sleep(Duration::from_millis(40u64)).await;
+        assert_eq!(Some(Duration::ZERO), session.expires_in(&reply_timeout));
+
+        session.replied();
+        assert!(session.awaiting_reply_for().is_none());
+        assert!(session.expires_in(&reply_timeout).unwrap() > Duration::from_secs(29u64));
+
+        let never = Settings {
+            idle_timeout: None,
+            ..settings(None)
+        };
+        assert!(session.expires_in(&never).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ReplySockets_get() {
+        let create = |_| {
+            let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16))?;
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket)
+        };
+        let orig_dst = |i: u8| SocketAddr::new(Ipv4Addr::new(10u8, 0u8, 0u8, i).into(), 53u16);
+        let reply_sockets = ReplySockets::new(2usize, create);
+
+        let first = reply_sockets.get(orig_dst(1u8)).unwrap();
+        let second = reply_sockets.get(orig_dst(2u8)).unwrap();
+        assert!(Arc::ptr_eq(&first, &reply_sockets.get(orig_dst(1u8)).unwrap()));
+
+        // the second one is the least recently used
+        reply_sockets.get(orig_dst(3u8)).unwrap();
+        assert!(Arc::ptr_eq(&first, &reply_sockets.get(orig_dst(1u8)).unwrap()));
+        assert!(!Arc::ptr_eq(&second, &reply_sockets.get(orig_dst(2u8)).unwrap()));
+        assert_eq!(2usize, reply_sockets.sockets.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_UdpSessions() {
+        let client = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 40000u16);
+        let orig_dst = SocketAddr::new(Ipv4Addr::new(10u8, 0u8, 0u8, 1u8).into(), 53u16);
+        let key = (client, orig_dst);
+        let sessions = UdpSessions::default();
+
+        assert!(sessions.get(&key).is_none());
+
+        sessions.insert(key, Arc::new(UdpSession::new(upstream_addr(), None, "lan".into(), settings(None), None)));
+        assert_eq!(upstream_addr(), sessions.get(&key).unwrap().upstream_addr);
+        assert!(sessions.get(&(orig_dst, client)).is_none());
+
+        sessions.remove(&key);
+        assert!(sessions.get(&key).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_udp_session_refused() {
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16)).await.unwrap();
+        let src = client.local_addr().unwrap();
+        let orig_dst = SocketAddr::new(Ipv4Addr::new(10u8, 0u8, 0u8, 1u8).into(), 53u16);
+        let key = (src, orig_dst);
+
+        // nothing listens at the upstream address yet
+        let upstream_addr = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let session = Arc::new(UdpSession::new(upstream_addr, None, "lan".into(), settings(None), None));
+        session.try_send(b"ping").unwrap();
+
+        let sessions = Arc::new(UdpSessions::default());
+        sessions.insert(key, session.clone());
+        let create = |_| {
+            let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0u16))?;
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket)
+        };
+        let (tx, rx) = watch::channel(Actions::INIT);
+        let config = Arc::new(ArcSwap::from_pointee(runtime_configs(r#"{ "udp_port": 8080 }"#)));
+        let permit = Arc::new(ConnLimit::new(1usize).unwrap())
+            .try_acquire()
+            .unwrap();
+        let task = tokio::spawn(udp_session(
+            key,
+            session.clone(),
+            sessions.clone(),
+            Arc::new(ReplySockets::new(1usize, create)),
+            rx,
+            config,
+            permit,
+        ));
+
+        // the refusal of the first datagram, received before the first reply, keeps the session open
+        sleep(Duration::from_millis(100u64)).await;
+        assert!(!task.is_finished());
+        let local_addr = session.upstream.get().unwrap().local_addr().unwrap();
+        let mut upstream = UdpSocket::bind(upstream_addr).await.unwrap();
+        upstream.send_to(b"pong", local_addr).await.unwrap();
+        let mut buf = [0u8; 32];
+        let len = timeout(Duration::from_secs(1u64), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"pong", &buf[..len]);
+
+        // nor does a refusal reported by a send drop the datagram
+        drop(upstream);
+        session.try_send(b"lost").unwrap();
+        sleep(Duration::from_millis(100u64)).await;
+        upstream = UdpSocket::bind(upstream_addr).await.unwrap();
+        session.try_send(b"ping").unwrap();
+        let len = upstream.recv(&mut buf).await.unwrap();
+        assert_eq!(b"ping", &buf[..len]);
+
+        tx.send_replace(Actions::SHUTDOWN);
+        task.await.unwrap();
+        assert!(sessions.get(&key).is_none());
+    }
+}
@@ -0,0 +1,218 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+/*!
+ * Copyright (C) 2025 Subham Pal
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ */
+
+use arc_swap::ArcSwap;
+use log::{error, info, warn};
+use sd_notify::{NotifyState, notify};
+use std::{process::ExitCode, sync::Arc};
+use tokio::{sync::watch, task::JoinSet};
+
+mod handlers;
+mod utils;
+
+#[cfg(feature = "bench")]
+pub use handlers::bench;
+
+use crate::{
+    handlers::{
+        forwarders::{tcp_forwarder, udp_forwarder},
+        health_checker::health_checker,
+        resolver::{resolve_pending, resolver},
+        signal_handler::{listen_status, signal_handler},
+        udp_sessions::ReplySockets,
+    },
+    utils::{
+        structs::{Actions, Args, RuntimeConfigs},
+        utils::{banner, check_return_routing, enable_logging, is_capable, read_config},
+    },
+};
+
+/// Runs the proxy until it's shut down, or fails to start
+#[cfg(target_os = "linux")]
+pub async fn run() -> ExitCode {
+    let capable = match is_capable() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        },
+    };
+
+    if !capable {
+        eprintln!("Both CAP_NET_ADMIN & CAP_NET_BIND_SERVICE need to be effective");
+        return ExitCode::FAILURE;
+    }
+
+    let args = match Args::new() {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        },
+    };
+
+    let _handle = match enable_logging(args.log_dir.as_ref()) {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        },
+    };
+
+    banner!("banner.txt");
+
+    info!("Application starting...");
+
+    let configs = match read_config(&args.config_file).await {
+        Ok(c) => match RuntimeConfigs::try_from(&c) {
+            Ok(rc) => Arc::new(ArcSwap::from_pointee(rc)),
+            Err(e) => {
+                error!("Invalid configuration - {e}");
+                return ExitCode::FAILURE;
+            },
+        },
+        Err(e) => {
+            error!("{e}");
+            return ExitCode::FAILURE;
+        },
+    };
+
+    if let Err(e) = check_return_routing(&configs.load().spoofing_addrs()).await {
+        warn!("Upstream replies to spoofed-source connections may not reach the proxy - {e}");
+    }
+
+    // hostname upstreams get their addresses before the forwarders start
+    if let Err(e) = resolve_pending(&configs.load()).await {
+        warn!("Upstream hostnames left to the resolver - {e}");
+    }
+
+    let (tx, rx) = watch::channel(Actions::INIT);
+    let mut tasks = JoinSet::new();
+
+    {
+        let tx = tx.clone();
+        let rx = rx.clone();
+        let configs = configs.clone();
+        let label = "Shutdown handler";
+
+        tasks.spawn(async move {
+            match signal_handler(tx.clone(), rx, &args.config_file, configs).await {
+                Ok(_) => Ok(((), label)),
+                Err(e) => Err((e, label)),
+            }
+        });
+    }
+
+    let workers = configs.load().workers;
+    info!("Starting {workers} forwarder workers per protocol...");
+
+    // every UDP worker sends replies from the same sockets
+    let reply_sockets = Arc::new(ReplySockets::default());
+
+    for _ in 0..workers {
+        {
+            let rx = rx.clone();
+            let configs = configs.clone();
+            let reply_sockets = reply_sockets.clone();
+            let label = "UDP forwarder";
+
+            tasks.spawn(async move {
+                match udp_forwarder(rx, configs, reply_sockets).await {
+                    Ok(_) => Ok(((), label)),
+                    Err(e) => Err((e, label)),
+                }
+            });
+        }
+
+        {
+            let rx = rx.clone();
+            let configs = configs.clone();
+            let label = "TCP forwarder";
+
+            tasks.spawn(async move {
+                match tcp_forwarder(rx, configs).await {
+                    Ok(_) => Ok(((), label)),
+                    Err(e) => Err((e, label)),
+                }
+            });
+        }
+    }
+
+    {
+        let rx = rx.clone();
+        let configs = configs.clone();
+        let label = "Resolver";
+
+        tasks.spawn(async move {
+            match resolver(rx, configs).await {
+                Ok(_) => Ok(((), label)),
+                Err(e) => Err((e, label)),
+            }
+        });
+    }
+
+    {
+        let rx = rx.clone();
+        let configs = configs.clone();
+        let label = "Health checker";
+
+        tasks.spawn(async move {
+            match health_checker(rx, configs).await {
+                Ok(_) => Ok(((), label)),
+                Err(e) => Err((e, label)),
+            }
+        });
+    }
+
+    info!("Application started");
+
+    if let Err(e) = notify(false, &[NotifyState::Ready]) {
+        warn!("Systemd READY notify failed - {e}");
+    }
+
+    if let Err(e) = notify(false, &[NotifyState::Status(&listen_status(&configs.load()))]) {
+        warn!("Systemd STATUS notify failed - {e}");
+    }
+
+    let mut stopping = false;
+    while let Some(res) = tasks.join_next().await {
+        match res {
+            Ok(Ok((_, l))) => info!("{l} - exited cleanly"),
+            Ok(Err((e, l))) => {
+                if !stopping {
+                    stopping = true;
+                    tx.send_replace(Actions::STOP(l));
+                }
+
+                error!("{l} - error: {e}");
+            },
+            Err(e) => {
+                if !stopping {
+                    stopping = true;
+                    tx.send_replace(Actions::PANICKED);
+                }
+
+                error!("Task join error: {e}");
+            },
+        };
+    }
+
+    info!("Application shutting down...");
+
+    if let Err(e) = notify(false, &[NotifyState::Stopping]) {
+        warn!("Systemd STOPPING notify failed - {e}");
+    }
+
+    info!("Application shut down");
+    ExitCode::SUCCESS
+}
+
+#[cfg(not(target_os = "linux"))]
+compile_error!("This program is only supported in Linux!");
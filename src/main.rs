@@ -8,163 +8,10 @@
  * (at your option) any later version.
  */
 
-use arc_swap::ArcSwap;
-use log::{error, info, warn};
-use sd_notify::{NotifyState, notify};
-use std::{process::ExitCode, sync::Arc};
-use tokio::{sync::watch, task::JoinSet};
-
-mod handlers;
-mod utils;
-
-use crate::{
-    handlers::{
-        constants::LISTEN_IP,
-        forwarders::{tcp_forwarder, udp_forwarder},
-        signal_handler::signal_handler,
-    },
-    utils::{
-        structs::{Actions, Args, RuntimeConfigs},
-        utils::{banner, enable_logging, is_capable, read_config},
-    },
-};
+use std::process::ExitCode;
 
 #[cfg(target_os = "linux")]
 #[tokio::main]
 async fn main() -> ExitCode {
-    let capable = match is_capable() {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("{e}");
-            return ExitCode::FAILURE;
-        },
-    };
-
-    if !capable {
-        eprintln!("Both CAP_NET_ADMIN & CAP_NET_BIND_SERVICE need to be effective");
-        return ExitCode::FAILURE;
-    }
-
-    let args = match Args::new() {
-        Ok(a) => a,
-        Err(e) => {
-            eprintln!("{e}");
-            return ExitCode::FAILURE;
-        },
-    };
-
-    let _handle = match enable_logging(args.log_dir.as_ref()) {
-        Ok(handle) => handle,
-        Err(e) => {
-            eprintln!("{e}");
-            return ExitCode::FAILURE;
-        },
-    };
-
-    banner!("banner.txt");
-
-    info!("Application starting...");
-
-    let configs = match read_config(&args.config_file).await {
-        Ok(c) => Arc::new(ArcSwap::from_pointee(RuntimeConfigs::from(&c))),
-        Err(e) => {
-            error!("{e}");
-            return ExitCode::FAILURE;
-        },
-    };
-
-    let (tx, rx) = watch::channel(Actions::INIT);
-    let mut tasks = JoinSet::new();
-
-    {
-        let tx = tx.clone();
-        let rx = rx.clone();
-        let configs = configs.clone();
-        let label = "Shutdown handler";
-
-        tasks.spawn(async move {
-            match signal_handler(tx.clone(), rx, &args.config_file, configs).await {
-                Ok(_) => Ok(((), label)),
-                Err(e) => Err((e, label)),
-            }
-        });
-    }
-
-    {
-        let rx = rx.clone();
-        let configs = configs.clone();
-        let label = "UDP forwarder";
-
-        tasks.spawn(async move {
-            match udp_forwarder(rx, configs).await {
-                Ok(_) => Ok(((), label)),
-                Err(e) => Err((e, label)),
-            }
-        });
-    }
-
-    {
-        let rx = rx.clone();
-        let configs = configs.clone();
-        let label = "TCP forwarder";
-
-        tasks.spawn(async move {
-            match tcp_forwarder(rx, configs).await {
-                Ok(_) => Ok(((), label)),
-                Err(e) => Err((e, label)),
-            }
-        });
-    }
-
-    info!("Application started");
-
-    if let Err(e) = notify(false, &[NotifyState::Ready]) {
-        warn!("Systemd READY notify failed - {e}");
-    }
-
-    if let Err(e) = notify(
-        false,
-        &[NotifyState::Status(&format!(
-            "Configured to listen at {}:{}",
-            LISTEN_IP,
-            configs.load().port
-        ))],
-    ) {
-        warn!("Systemd STATUS notify failed - {e}");
-    }
-
-    let mut stopping = false;
-    while let Some(res) = tasks.join_next().await {
-        match res {
-            Ok(Ok((_, l))) => info!("{l} - exited cleanly"),
-            Ok(Err((e, l))) => {
-                if !stopping {
-                    stopping = true;
-                    tx.send_replace(Actions::STOP(l));
-                }
-
-                error!("{l} - error: {e}");
-            },
-            Err(e) => {
-                if !stopping {
-                    stopping = true;
-                    tx.send_replace(Actions::PANICKED);
-                }
-
-                error!("Task join error: {e}");
-            },
-        };
-    }
-
-    info!("Application shutting down...");
-
-    if let Err(e) = notify(false, &[NotifyState::Stopping]) {
-        warn!("Systemd STOPPING notify failed - {e}");
-    }
-
-    info!("Application shut down");
-    ExitCode::SUCCESS
+    krustacean::run().await
 }
-
-#[cfg(not(target_os = "linux"))]
-compile_error!("This program is only supported in Linux!");
@@ -1,4 +1,9 @@
-use std::{ffi::c_int, process, sync::LazyLock};
+use std::{
+    ffi::c_int,
+    net::{IpAddr, Ipv4Addr},
+    process,
+    sync::LazyLock,
+};
 
 use const_format::concatcp;
 
@@ -21,3 +26,45 @@ pub(super) const LOG_FILE_NAME: &str = concatcp!(env!("CARGO_PKG_NAME"), ".log")
 
 /// Log file name
 pub(super) const CONFIG_FILE_NAME: &str = concatcp!(env!("CARGO_PKG_NAME"), ".json");
+
+/// Default idle timeout of a UDP session in seconds
+pub(super) const DEFAULT_UDP_IDLE_TIMEOUT: u64 = 30;
+
+/// Default timeout of an upstream connection attempt in seconds
+pub(super) const DEFAULT_CONNECT_TIMEOUT: u64 = 2;
+
+/// Default TCP relay buffer size, 4KB
+pub(super) const DEFAULT_TCP_BUFFER_SIZE: usize = 4096;
+
+/// Default wait for forwarder tasks to finish on shutdown in seconds
+pub(super) const DEFAULT_DRAIN_TIMEOUT: u64 = 5;
+
+/// Default TCP connection backlog and UDP session limit
+pub(super) const DEFAULT_BACKLOG: u32 = 100;
+
+/// Largest UDP payload over IPv4 - 65535 bytes less the IP & UDP headers
+pub(super) const MAX_UDP_PAYLOAD: usize = 65507;
+
+/// Default interval between health checks of an upstream in seconds
+pub(super) const DEFAULT_HEALTH_INTERVAL: u64 = 5;
+
+/// Default health check timeout in seconds
+pub(super) const DEFAULT_HEALTH_TIMEOUT: u64 = 2;
+
+/// Default consecutive successful health checks to mark an upstream up
+pub(super) const DEFAULT_HEALTH_RISE: u32 = 2;
+
+/// Default consecutive failed health checks to mark an upstream down
+pub(super) const DEFAULT_HEALTH_FALL: u32 = 3;
+
+/// Default TCP connection attempts to upstreams, including the first one
+pub(super) const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+
+/// Default delay before retrying the same upstream in milliseconds
+pub(super) const DEFAULT_RETRY_BACKOFF: u64 = 100;
+
+/// Default deadline of all TCP connection attempts in seconds
+pub(super) const DEFAULT_RETRY_DEADLINE: u64 = 5;
+
+/// Default proxy listen IPs - `127.0.0.2` only, IPv6 being opted into by listing `::1`
+pub(super) const DEFAULT_LISTEN_IPS: [IpAddr; 1] = [IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))];
@@ -0,0 +1,254 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use log::warn;
+use serde::Deserialize;
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// What a TCP forwarder does once the global connection limit is reached
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OverloadAction {
+    /// Stop accepting, leaving new connections in the kernel backlog until a session ends
+    #[default]
+    Pause,
+    /// Accept new connections & reset them right away
+    Reset,
+}
+
+/// Cap on concurrent sessions, each one holding a permit until it ends
+///
+/// * Sessions refused a permit are counted as rejected, those which had to wait for one as waited
+/// * The cap can be resized while sessions are open, those beyond a lowered cap giving their permit back as they end
+#[derive(Debug)]
+pub(crate) struct ConnLimit {
+    max: AtomicUsize,
+    permits: Arc<Semaphore>,
+    /// Permits to take back from ending sessions after the cap was lowered
+    owed: AtomicUsize,
+    active: AtomicUsize,
+    rejected: AtomicU64,
+    waited: AtomicU64,
+}
+
+/// Permit of an open session, given back to its [`ConnLimit`] once dropped
+#[derive(Debug)]
+pub(crate) struct ConnPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    limit: Arc<ConnLimit>,
+}
+
+impl Drop for ConnPermit {
+    fn drop(&mut self) {
+        self.limit.active.fetch_sub(1usize, Ordering::Relaxed);
+
+        // a lowered cap takes the permit back instead of letting another session in
+        let owed = self
+            .limit
+            .owed
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |o| o.checked_sub(1usize))
+            .is_ok();
+        if owed && let Some(p) = self.permit.take() {
+            p.forget();
+        }
+    }
+}
+
+impl ConnLimit {
+    pub(crate) fn new(max: usize) -> Result<Self, String> {
+        if max == 0usize {
+            return Err("Connection limit must be positive".into());
+        }
+
+        Ok(Self {
+            max: AtomicUsize::new(max),
+            permits: Arc::new(Semaphore::new(max)),
+            owed: AtomicUsize::new(0usize),
+            active: AtomicUsize::new(0usize),
+            rejected: AtomicU64::new(0u64),
+            waited: AtomicU64::new(0u64),
+        })
+    }
+
+    pub(crate) fn max(&self) -> usize {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// Sessions currently holding a permit
+    pub(crate) fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Sessions refused a permit so far
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Sessions which waited for a permit so far
+    pub(crate) fn waited(&self) -> u64 {
+        self.waited.load(Ordering::Relaxed)
+    }
+
+    /// Changes the cap, sessions already open staying counted against it
+    pub(crate) fn resize(&self, max: usize) {
+        let old = self.max.swap(max, Ordering::AcqRel);
+
+        if max > old {
+            // permits still owed are cancelled before new ones are added
+            let extra = max - old;
+            let owed = self
+                .owed
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |o| Some(o.saturating_sub(extra)))
+                .unwrap_or_default();
+            self.permits.add_permits(extra - owed.min(extra));
+        } else if max < old {
+            let forgotten = self.permits.forget_permits(old - max);
+            self.owed.fetch_add(old - max - forgotten, Ordering::AcqRel);
+        }
+    }
+
+    fn permit(self: &Arc<Self>, permit: OwnedSemaphorePermit) -> ConnPermit {
+        self.active.fetch_add(1usize, Ordering::Relaxed);
+        ConnPermit {
+            permit: Some(permit),
+            limit: self.clone(),
+        }
+    }
+
+    /// Permit for a new session if the limit isn't reached, else the sessions rejected so far
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Result<ConnPermit, u64> {
+        match self.permits.clone().try_acquire_owned() {
+            Ok(p) => Ok(self.permit(p)),
+            Err(_) => Err(self.rejected.fetch_add(1u64, Ordering::Relaxed) + 1u64),
+        }
+    }
+
+    /// Permit for a new session, waiting for one to end if the limit is reached
+    pub(crate) async fn acquire(self: &Arc<Self>) -> ConnPermit {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return self.permit(permit);
+        }
+
+        let waited = self.waited.fetch_add(1u64, Ordering::Relaxed) + 1u64;
+        warn!(
+            "Limit of {} concurrent connections reached, waiting for one to end ({waited} waited so far)...",
+            self.max()
+        );
+
+        // never closed
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Connection limit semaphore closed");
+        self.permit(permit)
+    }
+}
+
+impl fmt::Display for ConnLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} active, {} rejected, {} waited",
+            self.active(),
+            self.max(),
+            self.rejected(),
+            self.waited()
+        )
+    }
+}
+
+impl PartialEq for ConnLimit {
+    fn eq(&self, other: &Self) -> bool {
+        self.max() == other.max()
+    }
+}
+
+impl Eq for ConnLimit {}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[test]
+    fn test_ConnLimit_try_acquire() {
+        assert!(ConnLimit::new(0usize).is_err());
+
+        let limit = Arc::new(ConnLimit::new(2usize).unwrap());
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        assert_eq!(2usize, limit.active());
+        assert_eq!(Err(1u64), limit.try_acquire().map(|_| ()));
+        assert_eq!(Err(2u64), limit.try_acquire().map(|_| ()));
+
+        drop(first);
+        assert_eq!(1usize, limit.active());
+        assert!(limit.try_acquire().is_ok());
+        assert_eq!("1 of 2 active, 2 rejected, 0 waited", limit.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_ConnLimit_acquire() {
+        let limit = Arc::new(ConnLimit::new(1usize).unwrap());
+        let permit = limit.acquire().await;
+
+        let waiting = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire().await }
+        });
+        assert!(
+            timeout(Duration::from_millis(50u64), limit.acquire())
+                .await
+                .is_err()
+        );
+
+        drop(permit);
+        assert!(timeout(Duration::from_secs(1u64), waiting).await.is_ok());
+        assert_eq!(2u64, limit.waited());
+    }
+
+    #[test]
+    fn test_ConnLimit_resize() {
+        let limit = Arc::new(ConnLimit::new(3usize).unwrap());
+        let permits = (0..3)
+            .map(|_| limit.try_acquire().unwrap())
+            .collect::<Vec<_>>();
+
+        // the sessions still open count against the lowered cap
+        limit.resize(1usize);
+        let mut permits = permits.into_iter();
+        drop(permits.next());
+        assert!(limit.try_acquire().is_err());
+        drop(permits.next());
+        assert!(limit.try_acquire().is_err());
+        drop(permits.next());
+        let only = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_err());
+
+        // raising it lets more sessions in right away
+        limit.resize(3usize);
+        let _second = limit.try_acquire().unwrap();
+        let _third = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_err());
+        assert_eq!(3usize, limit.active());
+
+        // owed permits are cancelled by a raise before any is added
+        limit.resize(1usize);
+        limit.resize(2usize);
+        drop(only);
+        assert!(limit.try_acquire().is_err());
+        assert_eq!(5u64, limit.rejected());
+    }
+}
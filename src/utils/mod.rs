@@ -2,5 +2,7 @@
 
 pub(self) mod cap_bindings;
 pub(super) mod constants;
+pub(super) mod limits;
 pub(super) mod structs;
+pub(super) mod upstreams;
 pub(super) mod utils;
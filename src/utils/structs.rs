@@ -1,17 +1,29 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use libc::IFNAMSIZ;
 use serde::Deserialize;
 use std::{
     collections::{HashMap, HashSet},
     env::{self, VarError},
     error::Error,
-    fmt,
-    net::Ipv4Addr,
+    fmt, iter,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::PathBuf,
+    str::FromStr,
     sync::Arc,
+    thread,
+    time::Duration,
 };
 
-use super::constants::CONFIG_FILE_NAME;
+use super::{
+    constants::{
+        CONFIG_FILE_NAME, DEFAULT_BACKLOG, DEFAULT_CONNECT_TIMEOUT, DEFAULT_DRAIN_TIMEOUT, DEFAULT_HEALTH_FALL, DEFAULT_HEALTH_INTERVAL,
+        DEFAULT_HEALTH_RISE, DEFAULT_HEALTH_TIMEOUT, DEFAULT_LISTEN_IPS, DEFAULT_RETRY_ATTEMPTS, DEFAULT_RETRY_BACKOFF, DEFAULT_RETRY_DEADLINE,
+        DEFAULT_TCP_BUFFER_SIZE, DEFAULT_UDP_IDLE_TIMEOUT, MAX_UDP_PAYLOAD,
+    },
+    limits::{ConnLimit, OverloadAction},
+    upstreams::{HealthCheck, Host, Lease, Member, Retry, Strategy, UpstreamPool},
+};
 
 /// Logging error structure
 #[derive(Debug)]
@@ -60,65 +72,1378 @@ impl Args {
 /// Application configuration structure
 #[derive(Debug, Deserialize, Eq, PartialEq)]
 pub(crate) struct Configs {
-    pub(super) port: u16,
+    /// Top-level listener, enabled when it has a port or rules
+    #[serde(flatten)]
+    pub(super) main: ListenerConfigs,
+    /// Additional listeners, each with its own rules
+    #[serde(default)]
+    pub(super) listeners: Vec<ListenerConfigs>,
+    /// Seconds after which an idle UDP session is expired
+    #[serde(default = "default_udp_idle_timeout")]
+    pub(super) udp_idle_timeout: u64,
+    /// Seconds a UDP session waits for an upstream reply to a client datagram before being expired, unlimited if unset
+    #[serde(default)]
+    pub(super) udp_reply_timeout: Option<u64>,
+    /// Seconds allowed for each upstream connection attempt
+    #[serde(default = "default_connect_timeout")]
+    pub(super) connect_timeout: u64,
+    /// Seconds without any byte relayed in either direction after which a TCP session is closed, never if unset
+    #[serde(default)]
+    pub(super) tcp_idle_timeout: Option<u64>,
+    /// Seconds after which a TCP session is closed however active, unlimited if unset
+    #[serde(default)]
+    pub(super) tcp_max_lifetime: Option<u64>,
+    /// Bytes buffered per direction of a TCP session
+    #[serde(default = "default_tcp_buffer_size")]
+    pub(super) tcp_buffer_size: usize,
+    /// Seconds sessions are given to finish on shutdown
+    #[serde(default = "default_drain_timeout")]
+    pub(super) drain_timeout: u64,
+    /// TCP connection backlog of each forwarder worker & UDP session limit shared by all of them
+    #[serde(default = "default_backlog")]
+    pub(super) backlog: u32,
+    /// Bytes received per UDP datagram, larger datagrams being dropped as truncated
+    #[serde(default = "default_udp_buffer_size")]
+    pub(super) udp_buffer_size: usize,
+    /// Nameservers resolving upstream hostnames, the system ones being used if empty
+    #[serde(default)]
+    pub(super) nameservers: Vec<IpAddr>,
+    /// Relay TCP sessions with splice(2) instead of copying through user space
+    #[serde(default)]
+    pub(super) tcp_splice: bool,
+    /// Forwarder workers per protocol, each with its own SO_REUSEPORT sockets, defaulting to the CPU count
+    #[serde(default)]
+    pub(super) workers: Option<usize>,
+    /// Firewall mark set with SO_MARK on every upstream socket, so the proxy's own traffic isn't intercepted again
+    ///
+    /// * Must differ from the TPROXY mark, whose `ip rule` routes to the local table & would loop the traffic back in
+    /// * The firewall must let traffic carrying it through before the TPROXY rules
+    #[serde(default)]
+    pub(super) upstream_mark: Option<Fwmark>,
+    /// Concurrent TCP sessions across all listeners & workers, unlimited if unset
+    #[serde(default)]
+    pub(super) tcp_max_connections: Option<usize>,
+    /// What to do with new TCP connections once `tcp_max_connections` is reached
+    #[serde(default)]
+    pub(super) tcp_overload: OverloadAction,
+}
+
+/// Listener configuration structure
+///
+/// * `port` is used by both protocols unless overridden by `tcp_port` or `udp_port`
+/// * A protocol without a port is not listened for
+/// * `accept_proxy` makes TCP connections come from a load balancer instead of TPROXY, each one opened by a PROXY protocol header
+/// * Only peers in `proxy_from` may send that header, as it sets the client address & original destination. Passthrough & `spoof_source` rules are refused there, since a forged header would make them relay to or spoof any address.
+#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
+pub(super) struct ListenerConfigs {
+    #[serde(default)]
+    pub(super) name: Option<String>,
+    /// IPs the listener binds to
+    #[serde(default = "default_listen")]
+    pub(super) listen: Vec<IpAddr>,
+    #[serde(default)]
+    pub(super) port: Option<u16>,
+    #[serde(default)]
+    pub(super) tcp_port: Option<u16>,
+    #[serde(default)]
+    pub(super) udp_port: Option<u16>,
+    #[serde(default)]
     pub(super) udp: HashSet<Forwarders>,
+    #[serde(default)]
     pub(super) tcp: HashSet<Forwarders>,
+    /// What to do with intercepted traffic no rule matches
+    #[serde(default)]
+    pub(super) default_action: DefaultAction,
+    /// Take the client address & original destination of TCP connections from their PROXY protocol v1 or v2 header
+    #[serde(default)]
+    pub(super) accept_proxy: bool,
+    /// Load balancers trusted to send the PROXY protocol header, required with `accept_proxy`
+    #[serde(default)]
+    pub(super) proxy_from: Vec<IpNet>,
+}
+
+impl ListenerConfigs {
+    fn is_enabled(&self) -> bool {
+        self.port.is_some() || self.tcp_port.is_some() || self.udp_port.is_some() || !self.udp.is_empty() || !self.tcp.is_empty()
+    }
+}
+
+#[inline(always)]
+fn default_listen() -> Vec<IpAddr> {
+    DEFAULT_LISTEN_IPS.to_vec()
+}
+
+#[inline(always)]
+const fn default_udp_idle_timeout() -> u64 {
+    DEFAULT_UDP_IDLE_TIMEOUT
+}
+
+#[inline(always)]
+const fn default_udp_buffer_size() -> usize {
+    MAX_UDP_PAYLOAD
+}
+
+#[inline(always)]
+const fn default_connect_timeout() -> u64 {
+    DEFAULT_CONNECT_TIMEOUT
+}
+
+#[inline(always)]
+const fn default_tcp_buffer_size() -> usize {
+    DEFAULT_TCP_BUFFER_SIZE
+}
+
+#[inline(always)]
+const fn default_drain_timeout() -> u64 {
+    DEFAULT_DRAIN_TIMEOUT
+}
+
+#[inline(always)]
+const fn default_backlog() -> u32 {
+    DEFAULT_BACKLOG
 }
 
 /// Forwarder configuration structure
+///
+/// * `orig_ip` optionally restricts the rule to an original destination IP or CIDR
+/// * `orig_port` is either a single port or an inclusive range like `"8000-8999"`
+/// * `forward` rules need either a single `upstream` & `upstream_port` or a pool of `upstreams`
+/// * `passthrough` rules can't have any upstream
+/// * An upstream is an IP or a hostname
+/// * `connect_timeout`, `idle_timeout`, `reply_timeout`, `max_lifetime`, `buffer_size` & `mark` override the global settings for the rule
+/// * `socket_options` are set on both the client & upstream sockets of the TCP sessions of the rule
+/// * `spoof_source` makes upstream connections come from the client address, which needs the upstream replies routed back to the proxy
+/// * `bind_address` & `interface` pick the local IP & the interface upstream traffic leaves through, `bind_address` excluding `spoof_source`
+/// * `proxy_protocol` prepends a PROXY protocol header carrying the client address & original destination, UDP rules supporting v2 only
 #[derive(Debug, Deserialize, Eq, PartialEq, Hash)]
 pub(super) struct Forwarders {
-    pub(super) upstream_ip: Ipv4Addr,
-    pub(super) upstream_port: u16,
-    pub(super) orig_port: u16,
+    #[serde(default, alias = "upstream_ip")]
+    pub(super) upstream: Option<Host>,
+    #[serde(default)]
+    pub(super) upstream_port: Option<u16>,
+    #[serde(default)]
+    pub(super) upstreams: Vec<UpstreamConfigs>,
+    /// How an upstream is picked from `upstreams`
+    #[serde(default)]
+    pub(super) strategy: Strategy,
+    #[serde(default)]
+    pub(super) health_check: Option<HealthCheckConfigs>,
+    /// Retry of failed upstream connections, used by TCP rules only
+    #[serde(default)]
+    pub(super) connect_retry: Option<RetryConfigs>,
+    /// Concurrent sessions of the rule, further connections being reset, refused on UDP rules whose sessions `backlog` caps
+    #[serde(default)]
+    pub(super) max_connections: Option<usize>,
+    /// Seconds, used by TCP rules only
+    #[serde(default)]
+    pub(super) connect_timeout: Option<u64>,
+    /// Seconds
+    #[serde(default)]
+    pub(super) idle_timeout: Option<u64>,
+    /// Seconds, used by UDP rules only
+    #[serde(default)]
+    pub(super) reply_timeout: Option<u64>,
+    /// Seconds, used by TCP rules only
+    #[serde(default)]
+    pub(super) max_lifetime: Option<u64>,
+    /// Bytes of the TCP relay buffers or of the UDP reply buffers
+    #[serde(default)]
+    pub(super) buffer_size: Option<usize>,
+    /// Used by TCP rules only
+    #[serde(default)]
+    pub(super) socket_options: Option<SocketOptionsConfigs>,
+    /// Bind upstream sockets transparently to the client address & port
+    ///
+    /// Replies then need an `ip rule` matching their fwmark & looking up a table with a `local default` route, as for TPROXY.
+    /// Startup & reloads only check that some fwmark rule leads to such a table, not its mark nor that the firewall marks the replies.
+    /// The upstreams must be IPs of the family of the clients, as set by the listen IPs & `orig_ip`.
+    #[serde(default)]
+    pub(super) spoof_source: bool,
+    /// Firewall mark of the upstream sockets, overriding `upstream_mark` & likewise differing from the TPROXY mark
+    #[serde(default)]
+    pub(super) mark: Option<Fwmark>,
+    /// Local IP the upstream sockets are bound to
+    #[serde(default)]
+    pub(super) bind_address: Option<IpAddr>,
+    /// Interface the upstream sockets are bound to with SO_BINDTODEVICE
+    #[serde(default)]
+    pub(super) interface: Option<Interface>,
+    /// PROXY protocol header sent upstream at the start of a TCP connection or before every UDP datagram
+    #[serde(default)]
+    pub(super) proxy_protocol: Option<ProxyProtocol>,
+    #[serde(default)]
+    pub(super) orig_ip: Option<IpNet>,
+    pub(super) orig_port: PortRange,
+    #[serde(default)]
+    pub(super) action: RuleAction,
+}
+
+/// Upstream pool member configuration structure
+#[derive(Debug, Deserialize, Eq, PartialEq, Hash)]
+pub(super) struct UpstreamConfigs {
+    #[serde(alias = "ip")]
+    pub(super) host: Host,
+    pub(super) port: u16,
+    /// Share of the traffic relative to the other members, used by all strategies except `round_robin`
+    #[serde(default = "default_weight")]
+    pub(super) weight: u32,
+}
+
+#[inline(always)]
+const fn default_weight() -> u32 {
+    1u32
+}
+
+/// Health check configuration structure
+///
+/// * `interval` & `timeout` are in seconds
+/// * `payload` & `expect` are hex strings, used by UDP probes only
+#[derive(Debug, Deserialize, Eq, PartialEq, Hash)]
+pub(super) struct HealthCheckConfigs {
+    #[serde(default = "default_health_interval")]
+    pub(super) interval: u64,
+    #[serde(default = "default_health_timeout")]
+    pub(super) timeout: u64,
+    /// Consecutive successes to mark a down upstream up
+    #[serde(default = "default_health_rise")]
+    pub(super) rise: u32,
+    /// Consecutive failures to mark an up upstream down
+    #[serde(default = "default_health_fall")]
+    pub(super) fall: u32,
+    #[serde(default)]
+    pub(super) payload: Option<String>,
+    #[serde(default)]
+    pub(super) expect: Option<String>,
+}
+
+#[inline(always)]
+const fn default_health_interval() -> u64 {
+    DEFAULT_HEALTH_INTERVAL
+}
+
+#[inline(always)]
+const fn default_health_timeout() -> u64 {
+    DEFAULT_HEALTH_TIMEOUT
+}
+
+#[inline(always)]
+const fn default_health_rise() -> u32 {
+    DEFAULT_HEALTH_RISE
+}
+
+#[inline(always)]
+const fn default_health_fall() -> u32 {
+    DEFAULT_HEALTH_FALL
+}
+
+/// Decodes a hex string like `"0a1B"` into bytes
+fn decode_hex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2usize) {
+        return Err(format!("Hex string \"{s}\" has an odd length"));
+    }
+
+    (0..s.len())
+        .step_by(2usize)
+        .map(|i| {
+            s.get(i..i + 2usize)
+                .and_then(|b| u8::from_str_radix(b, 16u32).ok())
+                .ok_or_else(|| format!("Invalid hex string \"{s}\""))
+        })
+        .collect()
+}
+
+impl TryFrom<&HealthCheckConfigs> for HealthCheck {
+    type Error = String;
+
+    fn try_from(cfg: &HealthCheckConfigs) -> Result<Self, Self::Error> {
+        if cfg.interval == 0u64 || cfg.timeout == 0u64 {
+            return Err("Health check interval & timeout must be positive".into());
+        }
+
+        if cfg.rise == 0u32 || cfg.fall == 0u32 {
+            return Err("Health check rise & fall must be positive".into());
+        }
+
+        let expect = cfg.expect.as_deref().map(decode_hex).transpose()?;
+        if expect.as_ref().is_some_and(|e| e.is_empty()) {
+            return Err("Health check expect can't be empty".into());
+        }
+
+        Ok(Self {
+            interval: Duration::from_secs(cfg.interval),
+            timeout: Duration::from_secs(cfg.timeout),
+            rise: cfg.rise,
+            fall: cfg.fall,
+            payload: cfg
+                .payload
+                .as_deref()
+                .map(decode_hex)
+                .transpose()?
+                .unwrap_or_default(),
+            expect,
+        })
+    }
+}
+
+/// Connect retry configuration structure
+///
+/// * `attempts` includes the first connection attempt
+/// * `backoff` is in milliseconds & `deadline` in seconds
+#[derive(Debug, Deserialize, Eq, PartialEq, Hash)]
+pub(super) struct RetryConfigs {
+    #[serde(default = "default_retry_attempts")]
+    pub(super) attempts: u32,
+    #[serde(default = "default_retry_backoff")]
+    pub(super) backoff: u64,
+    #[serde(default = "default_retry_deadline")]
+    pub(super) deadline: u64,
+}
+
+#[inline(always)]
+const fn default_retry_attempts() -> u32 {
+    DEFAULT_RETRY_ATTEMPTS
+}
+
+#[inline(always)]
+const fn default_retry_backoff() -> u64 {
+    DEFAULT_RETRY_BACKOFF
+}
+
+#[inline(always)]
+const fn default_retry_deadline() -> u64 {
+    DEFAULT_RETRY_DEADLINE
+}
+
+impl TryFrom<&RetryConfigs> for Retry {
+    type Error = String;
+
+    fn try_from(cfg: &RetryConfigs) -> Result<Self, Self::Error> {
+        if cfg.attempts == 0u32 || cfg.deadline == 0u64 {
+            return Err("Connect retry attempts & deadline must be positive".into());
+        }
+
+        Ok(Self {
+            attempts: cfg.attempts,
+            backoff: Duration::from_millis(cfg.backoff),
+            deadline: Duration::from_secs(cfg.deadline),
+        })
+    }
+}
+
+/// TCP socket options configuration structure
+///
+/// * Keepalive `idle` & `interval` are in seconds, `user_timeout` in milliseconds
+/// * Options left unset keep the system defaults
+#[derive(Debug, Default, Deserialize, Eq, PartialEq, Hash)]
+pub(super) struct SocketOptionsConfigs {
+    #[serde(default)]
+    pub(super) keepalive: Option<KeepaliveConfigs>,
+    #[serde(default)]
+    pub(super) user_timeout: Option<u64>,
+    #[serde(default)]
+    pub(super) nodelay: Option<bool>,
+    #[serde(default)]
+    pub(super) send_buffer_size: Option<usize>,
+    #[serde(default)]
+    pub(super) recv_buffer_size: Option<usize>,
+}
+
+/// TCP keepalive configuration structure, an empty one enabling SO_KEEPALIVE with the system parameters
+#[derive(Debug, Default, Deserialize, Eq, PartialEq, Hash)]
+pub(super) struct KeepaliveConfigs {
+    #[serde(default)]
+    pub(super) idle: Option<u64>,
+    #[serde(default)]
+    pub(super) interval: Option<u64>,
+    #[serde(default)]
+    pub(super) count: Option<u32>,
+}
+
+/// TCP keepalive parameters, the system ones being used for those unset
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Keepalive {
+    /// Idle time before the first probe, TCP_KEEPIDLE
+    pub(crate) idle: Option<Duration>,
+    /// Time between probes, TCP_KEEPINTVL
+    pub(crate) interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped, TCP_KEEPCNT
+    pub(crate) count: Option<u32>,
+}
+
+/// Options of the client & upstream sockets of a TCP session, the system defaults being kept for those unset
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct SocketOptions {
+    /// SO_KEEPALIVE with its parameters
+    pub(crate) keepalive: Option<Keepalive>,
+    /// TCP_USER_TIMEOUT
+    pub(crate) user_timeout: Option<Duration>,
+    /// TCP_NODELAY
+    pub(crate) nodelay: Option<bool>,
+    /// SO_SNDBUF
+    pub(crate) send_buffer_size: Option<usize>,
+    /// SO_RCVBUF
+    pub(crate) recv_buffer_size: Option<usize>,
+}
+
+impl TryFrom<&SocketOptionsConfigs> for SocketOptions {
+    type Error = String;
+
+    fn try_from(cfg: &SocketOptionsConfigs) -> Result<Self, Self::Error> {
+        let keepalive = cfg.keepalive.as_ref().map(|k| Keepalive {
+            idle: k.idle.map(Duration::from_secs),
+            interval: k.interval.map(Duration::from_secs),
+            count: k.count,
+        });
+
+        let zero_keepalive = keepalive.is_some_and(|k| [k.idle, k.interval].contains(&Some(Duration::ZERO)) || k.count == Some(0u32));
+        if zero_keepalive || cfg.user_timeout == Some(0u64) {
+            return Err("Keepalive parameters & user timeout must be positive".into());
+        }
+
+        if [cfg.send_buffer_size, cfg.recv_buffer_size].contains(&Some(0usize)) {
+            return Err("Socket buffer sizes must be positive".into());
+        }
+
+        Ok(Self {
+            keepalive,
+            user_timeout: cfg.user_timeout.map(Duration::from_millis),
+            nodelay: cfg.nodelay,
+            send_buffer_size: cfg.send_buffer_size,
+            recv_buffer_size: cfg.recv_buffer_size,
+        })
+    }
+}
+
+/// Action of a matching rule
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum RuleAction {
+    /// Forward to the configured upstream
+    #[default]
+    Forward,
+    /// Forward to the original destination
+    Passthrough,
+}
+
+/// Action for traffic matching no rule
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum DefaultAction {
+    #[default]
+    Drop,
+    Passthrough,
+}
+
+/// IP network in CIDR notation, a bare IP being a host network
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[serde(try_from = "String")]
+pub(crate) struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub(crate) fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, String> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32u8,
+            IpAddr::V6(_) => 128u8,
+        };
+
+        if prefix_len > max_len {
+            return Err(format!("Prefix length {prefix_len} exceeds {max_len} for {addr}"));
+        }
+
+        Ok(Self {
+            addr: Self::mask(&addr, prefix_len),
+            prefix_len,
+        })
+    }
+
+    /// Clears the host bits of `addr`
+    fn mask(addr: &IpAddr, prefix_len: u8) -> IpAddr {
+        match addr {
+            IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(
+                u32::from(*v4)
+                    & u32::MAX
+                        .checked_shl(32u32 - prefix_len as u32)
+                        .unwrap_or(0u32),
+            )),
+            IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(
+                u128::from(*v6)
+                    & u128::MAX
+                        .checked_shl(128u32 - prefix_len as u32)
+                        .unwrap_or(0u128),
+            )),
+        }
+    }
+
+    pub(crate) fn contains(&self, ip: &IpAddr) -> bool {
+        self.addr.is_ipv4() == ip.is_ipv4() && Self::mask(ip, self.prefix_len) == self.addr
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+
+        let addr = IpAddr::from_str(addr).map_err(|e| format!("Invalid IP address \"{addr}\" - {e}"))?;
+        let prefix_len = match prefix_len {
+            Some(p) => u8::from_str(p).map_err(|e| format!("Invalid prefix length \"{p}\" - {e}"))?,
+            None if addr.is_ipv4() => 32u8,
+            None => 128u8,
+        };
+
+        Self::new(addr, prefix_len)
+    }
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::from_str(&s)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Inclusive port range, a single port being a range of one
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[serde(try_from = "PortSpec")]
+pub(crate) struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+/// Port as written in the configuration file - a number or a `"start-end"` string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortSpec {
+    Port(u16),
+    Range(String),
+}
+
+impl PortRange {
+    pub(crate) fn new(start: u16, end: u16) -> Result<Self, String> {
+        if start > end {
+            return Err(format!("Invalid port range {start}-{end}"));
+        }
+
+        Ok(Self { start, end })
+    }
+
+    pub(crate) fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+
+    /// Number of ports in the range
+    pub(crate) fn size(&self) -> u32 {
+        (self.end - self.start) as u32 + 1u32
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.start == self.end {
+            true => write!(f, "{}", self.start),
+            false => write!(f, "{}-{}", self.start, self.end),
+        }
+    }
+}
+
+impl TryFrom<PortSpec> for PortRange {
+    type Error = String;
+
+    fn try_from(spec: PortSpec) -> Result<Self, Self::Error> {
+        match spec {
+            PortSpec::Port(p) => Self::new(p, p),
+            PortSpec::Range(r) => {
+                let parse = |p: &str| u16::from_str(p.trim()).map_err(|e| format!("Invalid port \"{p}\" - {e}"));
+
+                match r.split_once('-') {
+                    Some((start, end)) => Self::new(parse(start)?, parse(end)?),
+                    None => {
+                        let port = parse(&r)?;
+                        Self::new(port, port)
+                    },
+                }
+            },
+        }
+    }
+}
+
+/// Non-zero firewall mark
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(try_from = "MarkSpec")]
+pub(crate) struct Fwmark(pub(crate) u32);
+
+/// Mark as written in the configuration file - a number or a `"0x3002"` hex string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MarkSpec {
+    Mark(u32),
+    Hex(String),
+}
+
+impl TryFrom<MarkSpec> for Fwmark {
+    type Error = String;
+
+    fn try_from(spec: MarkSpec) -> Result<Self, Self::Error> {
+        let mark = match spec {
+            MarkSpec::Mark(m) => m,
+            MarkSpec::Hex(h) => {
+                let digits = h
+                    .trim()
+                    .strip_prefix("0x")
+                    .or_else(|| h.trim().strip_prefix("0X"))
+                    .ok_or_else(|| format!("Invalid mark \"{h}\" - expected a 0x prefixed hex string"))?;
+
+                u32::from_str_radix(digits, 16).map_err(|e| format!("Invalid mark \"{h}\" - {e}"))?
+            },
+        };
+
+        match mark {
+            0u32 => Err("Mark must be non-zero".into()),
+            m => Ok(Self(m)),
+        }
+    }
+}
+
+/// Network interface name, at most `IFNAMSIZ - 1` bytes
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(try_from = "String")]
+pub(crate) struct Interface([u8; IFNAMSIZ]);
+
+impl Interface {
+    /// Name without the NUL padding, as expected by SO_BINDTODEVICE
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        let len = self.0.iter().position(|b| *b == 0u8).unwrap_or(IFNAMSIZ);
+        &self.0[..len]
+    }
+}
+
+impl TryFrom<String> for Interface {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        if name.is_empty() || name.len() >= IFNAMSIZ {
+            return Err(format!("Interface name \"{name}\" must be 1 to {} bytes long", IFNAMSIZ - 1usize));
+        }
+
+        if name
+            .bytes()
+            .any(|b| b == 0u8 || b == b'/' || b.is_ascii_whitespace())
+        {
+            return Err(format!("Invalid interface name \"{name}\""));
+        }
+
+        let mut bytes = [0u8; IFNAMSIZ];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(self.as_bytes()))
+    }
+}
+
+/// Version of the PROXY protocol header sent to upstreams
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ProxyProtocol {
+    /// Human readable, TCP only
+    V1,
+    /// Binary, TCP & UDP
+    V2,
+}
+
+/// Where intercepted traffic is sent to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Target {
+    Upstream(Arc<UpstreamPool>),
+    Passthrough,
+}
+
+impl Target {
+    /// Address to connect to for traffic from `src` originally destined to `orig_dst`
+    ///
+    /// * An upstream picked from a pool stays leased until the returned [`Lease`] is dropped
+    /// * [`None`] if every upstream of the pool is down
+    pub(crate) fn resolve(&self, src: &SocketAddr, orig_dst: &SocketAddr) -> Option<(SocketAddr, Option<Lease>)> {
+        match self {
+            Target::Upstream(pool) => pool.pick(src).map(|lease| (lease.addr(), Some(lease))),
+            Target::Passthrough => Some((*orig_dst, None)),
+        }
+    }
+}
+
+/// Session settings of a rule - the global ones, possibly overridden by the rule
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Settings {
+    /// Timeout of each upstream connection attempt
+    pub(crate) connect_timeout: Duration,
+    /// Inactivity after which a session is closed, never if `None`
+    pub(crate) idle_timeout: Option<Duration>,
+    /// Wait for an upstream reply to a client datagram after which a UDP session is closed, unlimited if `None`
+    pub(crate) reply_timeout: Option<Duration>,
+    /// Age after which a TCP session is closed, unlimited if `None`
+    pub(crate) max_lifetime: Option<Duration>,
+    /// TCP relay or UDP reply buffer size
+    pub(crate) buffer_size: usize,
+    /// Options of the TCP client & upstream sockets
+    pub(crate) socket_options: SocketOptions,
+    /// Upstream sockets bound to the client address & port with IP_TRANSPARENT
+    pub(crate) spoof_source: bool,
+    /// SO_MARK of the upstream sockets
+    pub(crate) mark: Option<u32>,
+    /// Local IP of the upstream sockets
+    pub(crate) bind_address: Option<IpAddr>,
+    /// SO_BINDTODEVICE of the upstream sockets
+    pub(crate) interface: Option<Interface>,
+    /// PROXY protocol header version sent upstream
+    pub(crate) proxy_protocol: Option<ProxyProtocol>,
+}
+
+impl Settings {
+    /// Settings of a rule overriding these ones
+    fn with(&self, fwd: &Forwarders) -> Result<Self, String> {
+        let secs = |t: Option<u64>| match t {
+            Some(0u64) => Err(format!("Timeouts of the rule for port {} must be positive", fwd.orig_port)),
+            t => Ok(t.map(Duration::from_secs)),
+        };
+
+        if fwd.buffer_size == Some(0usize) {
+            return Err(format!("Buffer size of the rule for port {} must be positive", fwd.orig_port));
+        }
+
+        let spoof_source = fwd.spoof_source || self.spoof_source;
+        let bind_address = fwd.bind_address.or(self.bind_address);
+        if let (true, Some(ip)) = (spoof_source, bind_address) {
+            return Err(format!(
+                "Rule for port {} can't both spoof the client address & bind to {ip}",
+                fwd.orig_port
+            ));
+        }
+
+        Ok(Self {
+            connect_timeout: secs(fwd.connect_timeout)?.unwrap_or(self.connect_timeout),
+            idle_timeout: secs(fwd.idle_timeout)?.or(self.idle_timeout),
+            reply_timeout: secs(fwd.reply_timeout)?.or(self.reply_timeout),
+            max_lifetime: secs(fwd.max_lifetime)?.or(self.max_lifetime),
+            buffer_size: fwd.buffer_size.unwrap_or(self.buffer_size),
+            socket_options: match &fwd.socket_options {
+                Some(cfg) => SocketOptions::try_from(cfg).map_err(|e| format!("{e} in the rule for port {}", fwd.orig_port))?,
+                None => self.socket_options,
+            },
+            spoof_source,
+            mark: fwd.mark.map(|m| m.0).or(self.mark),
+            bind_address,
+            interface: fwd.interface.or(self.interface),
+            proxy_protocol: fwd.proxy_protocol.or(self.proxy_protocol),
+        })
+    }
+}
+
+/// Forwarding rule, matched against the original destination
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Rule {
+    pub(crate) orig_ip: Option<IpNet>,
+    pub(crate) orig_port: PortRange,
+    pub(crate) target: Target,
+    pub(crate) limit: Option<Arc<ConnLimit>>,
+    pub(crate) settings: Settings,
+}
+
+impl Rule {
+    pub(crate) fn matches(&self, dst: &SocketAddr) -> bool {
+        self.orig_port.contains(dst.port()) && self.orig_ip.is_none_or(|net| net.contains(&dst.ip()))
+    }
+}
+
+impl TryFrom<(&Forwarders, &Settings)> for Rule {
+    type Error = String;
+
+    fn try_from((fwd, defaults): (&Forwarders, &Settings)) -> Result<Self, Self::Error> {
+        let members = match (&fwd.upstream, fwd.upstream_port) {
+            (Some(host), Some(port)) if fwd.upstreams.is_empty() => vec![Member {
+                host: host.clone(),
+                port,
+                weight: 1u32,
+            }],
+            (None, None) => fwd
+                .upstreams
+                .iter()
+                .map(|u| Member {
+                    host: u.host.clone(),
+                    port: u.port,
+                    weight: u.weight,
+                })
+                .collect(),
+            _ => {
+                return Err(format!(
+                    "Rule for port {} needs either both upstream & upstream_port or upstreams",
+                    fwd.orig_port
+                ));
+            },
+        };
+
+        let target = match (fwd.action, members.is_empty()) {
+            (RuleAction::Forward, _) => {
+                let pool = || {
+                    let health_check = fwd
+                        .health_check
+                        .as_ref()
+                        .map(HealthCheck::try_from)
+                        .transpose()?;
+                    let retry = fwd
+                        .connect_retry
+                        .as_ref()
+                        .map(Retry::try_from)
+                        .transpose()?;
+                    UpstreamPool::new(fwd.strategy, &members, health_check, retry)
+                };
+
+                Target::Upstream(Arc::new(pool().map_err(|e| format!("Forward rule for port {} - {e}", fwd.orig_port))?))
+            },
+            (RuleAction::Passthrough, true) => Target::Passthrough,
+            (RuleAction::Passthrough, false) => return Err(format!("Passthrough rule for port {} can't have an upstream", fwd.orig_port)),
+        };
+
+        let limit = fwd
+            .max_connections
+            .map(ConnLimit::new)
+            .transpose()
+            .map_err(|e| format!("Rule for port {} - {e}", fwd.orig_port))?;
+
+        Ok(Self {
+            orig_ip: fwd.orig_ip,
+            orig_port: fwd.orig_port,
+            target,
+            limit: limit.map(Arc::new),
+            settings: defaults.with(fwd)?,
+        })
+    }
+}
+
+/// What a rule does with its traffic, telling apart rules matching the same one
+fn rule_action(fwd: &Forwarders) -> String {
+    match (fwd.action, &fwd.upstream, fwd.upstream_port) {
+        (RuleAction::Passthrough, _, _) => "passing through".into(),
+        (RuleAction::Forward, Some(host), Some(port)) => format!("forwarding to {host}:{port}"),
+        (RuleAction::Forward, _, _) => format!(
+            "forwarding to {}",
+            fwd.upstreams
+                .iter()
+                .map(|u| format!("{}:{}", u.host, u.port))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Refuses rules spoofing the client source when an upstream may be of another IP family than the clients, as the spoofed bind would fail
+///
+/// * Clients are in the families of the listen IPs, narrowed to that of `orig_ip`
+/// * Hostname upstreams may resolve to either family
+fn check_spoofing_families(listener: &str, rules: &[Rule], listen: &[IpAddr]) -> Result<(), String> {
+    for rule in rules.iter().filter(|r| r.settings.spoof_source) {
+        // passthrough connects to the original destination, of the client's family
+        let Target::Upstream(pool) = &rule.target else {
+            continue;
+        };
+
+        let clients = listen
+            .iter()
+            .map(IpAddr::is_ipv6)
+            .filter(|v6| rule.orig_ip.is_none_or(|net| net.addr.is_ipv6() == *v6))
+            .collect::<Vec<_>>();
+        if pool.hostnames().next().is_some()
+            || pool
+                .upstreams()
+                .iter()
+                .any(|u| clients.iter().any(|v6| u.addr.is_ipv6() != *v6))
+        {
+            return Err(format!(
+                "Listener {listener} has a rule for port {} spoofing the client source whose upstreams may not be of the IP family of its clients",
+                rule.orig_port
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds rules ordered from the most to the least specific
+///
+/// * Longer destination prefixes come first, rules without `orig_ip` act as the fallback
+/// * Among equal prefixes, narrower port ranges come first
+/// * Rules with the same `orig_ip` & `orig_port` are refused, as which one matches would be left to chance
+fn sorted_rules(fwds: &HashSet<Forwarders>, defaults: &Settings) -> Result<Vec<Rule>, String> {
+    let all = fwds.iter().collect::<Vec<_>>();
+    for (i, a) in all.iter().enumerate() {
+        if let Some(b) = all[i + 1..]
+            .iter()
+            .find(|b| b.orig_ip == a.orig_ip && b.orig_port == a.orig_port)
+        {
+            let mut actions = [rule_action(a), rule_action(b)];
+            actions.sort();
+
+            return Err(match a.orig_ip {
+                Some(ip) => format!("Rule {} & rule {} both match {ip} port {}", actions[0], actions[1], a.orig_port),
+                None => format!("Rule {} & rule {} both match port {}", actions[0], actions[1], a.orig_port),
+            });
+        }
+    }
+
+    let mut rules = fwds
+        .iter()
+        .map(|f| Rule::try_from((f, defaults)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    rules.sort_by(|a, b| {
+        let prefix_len = |r: &Rule| r.orig_ip.map(|n| n.prefix_len);
+
+        prefix_len(b)
+            .cmp(&prefix_len(a))
+            .then(a.orig_port.size().cmp(&b.orig_port.size()))
+            .then(a.orig_port.cmp(&b.orig_port))
+            .then(a.orig_ip.cmp(&b.orig_ip))
+    });
+
+    Ok(rules)
+}
+
+/// Listener with the addresses it binds to and its rules
+#[derive(PartialEq, Eq)]
+pub(crate) struct Listener<M> {
+    pub(crate) name: String,
+    pub(crate) addrs: Vec<SocketAddr>,
+    pub(crate) map: M,
+    /// Peers trusted to open TCP connections with a PROXY protocol header, TPROXY interception being expected if `None`
+    pub(crate) proxy_from: Option<Vec<IpNet>>,
+}
+
+/// Every listen address paired with the listener binding it
+pub(crate) fn bindings<M>(listeners: &[Arc<Listener<M>>]) -> (Vec<SocketAddr>, Vec<Arc<Listener<M>>>) {
+    listeners
+        .iter()
+        .flat_map(|l| l.addrs.iter().map(move |addr| (*addr, l.clone())))
+        .unzip()
 }
 
 #[derive(PartialEq, Eq)]
 pub(crate) struct RuntimeConfigs {
-    pub(crate) port: u16,
-    pub(crate) udp_map: Arc<UdpMap>,
-    pub(crate) tcp_map: Arc<TcpMap>,
+    pub(crate) udp_listeners: Vec<Arc<Listener<UdpMap>>>,
+    pub(crate) tcp_listeners: Vec<Arc<Listener<TcpMap>>>,
+    pub(crate) udp_buffer_size: usize,
+    pub(crate) drain_timeout: Duration,
+    pub(crate) backlog: u32,
+    pub(crate) nameservers: Vec<IpAddr>,
+    pub(crate) tcp_splice: bool,
+    pub(crate) workers: usize,
+    /// Shared by every UDP forwarder worker & kept across configuration changes, `backlog` sessions at most
+    pub(crate) udp_limit: Arc<ConnLimit>,
+    /// Shared by every TCP forwarder worker & kept across configuration changes
+    pub(crate) tcp_limit: Option<Arc<ConnLimit>>,
+    pub(crate) tcp_overload: OverloadAction,
 }
 
-impl From<&Configs> for RuntimeConfigs {
-    fn from(cfg: &Configs) -> Self {
-        Self {
-            port: cfg.port,
-            udp_map: Arc::new(UdpMap(
-                cfg.udp
-                    .iter()
-                    .map(|u| (u.orig_port, (u.upstream_ip, u.upstream_port)))
-                    .collect(),
-            )),
-            tcp_map: Arc::new(TcpMap(
-                cfg.tcp
+impl RuntimeConfigs {
+    /// Listen addresses of the listeners with a rule spoofing the client source
+    pub(crate) fn spoofing_addrs(&self) -> Vec<SocketAddr> {
+        fn spoofing<M: ForwarderMap>(listeners: &[Arc<Listener<M>>]) -> impl Iterator<Item = SocketAddr> + '_ {
+            listeners
+                .iter()
+                .filter(|l| l.map.rules().iter().any(|r| r.settings.spoof_source))
+                .flat_map(|l| l.addrs.iter().copied())
+        }
+
+        spoofing(&self.udp_listeners)
+            .chain(spoofing(&self.tcp_listeners))
+            .collect()
+    }
+
+    /// Every upstream pool of the configuration
+    pub(crate) fn pools(&self) -> Vec<&Arc<UpstreamPool>> {
+        self.tcp_listeners
+            .iter()
+            .flat_map(|l| l.map.pools())
+            .chain(self.udp_listeners.iter().flat_map(|l| l.map.pools()))
+            .collect()
+    }
+
+    /// Gives the hostname members the addresses they resolved to in the current configuration
+    ///
+    /// * Pools are built without any, so they would have no upstream until the resolver catches up with a reload
+    pub(crate) fn carry_resolved(&self, current: &RuntimeConfigs) {
+        let known = current
+            .pools()
+            .into_iter()
+            .flat_map(|p| {
+                p.hostnames()
+                    .filter_map(|n| p.resolved(n).map(|ips| (n.to_string(), ips)))
+            })
+            .collect::<HashMap<_, _>>();
+
+        for pool in self.pools() {
+            for name in pool.hostnames() {
+                if let Some(ips) = known.get(name) {
+                    pool.set_resolved(name, ips);
+                }
+            }
+        }
+    }
+
+    /// Keeps the connection limits of the current configuration, resized to the new maximums
+    ///
+    /// * Open sessions hold permits of the current limits, so fresh ones would let in more than the maximum
+    /// * Rule limits are matched by listener name & rule `orig_ip`/`orig_port`
+    pub(crate) fn carry_limits(&mut self, current: &RuntimeConfigs) {
+        fn carry(new: &mut Option<Arc<ConnLimit>>, current: Option<&Arc<ConnLimit>>) {
+            if let (Some(limit), Some(current)) = (new.as_ref(), current) {
+                current.resize(limit.max());
+                *new = Some(current.clone());
+            }
+        }
+
+        current.udp_limit.resize(self.udp_limit.max());
+        self.udp_limit = current.udp_limit.clone();
+        carry(&mut self.tcp_limit, current.tcp_limit.as_ref());
+
+        for listener in self.tcp_listeners.iter_mut() {
+            let Some(old) = current
+                .tcp_listeners
+                .iter()
+                .find(|l| l.name == listener.name)
+            else {
+                continue;
+            };
+
+            // freshly built, so not shared yet
+            if let Some(listener) = Arc::get_mut(listener) {
+                for rule in listener.map.0.iter_mut() {
+                    let old_limit = old
+                        .map
+                        .rules()
+                        .iter()
+                        .find(|r| r.orig_ip == rule.orig_ip && r.orig_port == rule.orig_port)
+                        .and_then(|r| r.limit.as_ref());
+                    carry(&mut rule.limit, old_limit);
+                }
+            }
+        }
+    }
+
+    /// Session & connection limits with their names, the UDP & global TCP ones first
+    pub(crate) fn limits(&self) -> Vec<(String, &Arc<ConnLimit>)> {
+        let rules = self.tcp_listeners.iter().flat_map(|l| {
+            l.map.rules().iter().filter_map(move |r| {
+                r.limit.as_ref().map(|limit| match r.orig_ip {
+                    Some(ip) => (format!("{} rule for {ip} port {} connection", l.name, r.orig_port), limit),
+                    None => (format!("{} rule for port {} connection", l.name, r.orig_port), limit),
+                })
+            })
+        });
+
+        iter::once(("UDP session".to_string(), &self.udp_limit))
+            .chain(
+                self.tcp_limit
                     .iter()
-                    .map(|u| (u.orig_port, (u.upstream_ip, u.upstream_port)))
-                    .collect(),
-            )),
+                    .map(|limit| ("TCP connection".to_string(), limit)),
+            )
+            .chain(rules)
+            .collect()
+    }
+}
+
+impl TryFrom<&Configs> for RuntimeConfigs {
+    type Error = String;
+
+    fn try_from(cfg: &Configs) -> Result<Self, Self::Error> {
+        let mut udp_listeners = Vec::new();
+        let mut tcp_listeners = Vec::new();
+
+        if !(1usize..=MAX_UDP_PAYLOAD).contains(&cfg.udp_buffer_size) {
+            return Err(format!("UDP buffer size must be between 1 & {MAX_UDP_PAYLOAD} bytes"));
+        }
+
+        if cfg.udp_idle_timeout == 0u64
+            || cfg.connect_timeout == 0u64
+            || [cfg.udp_reply_timeout, cfg.tcp_idle_timeout, cfg.tcp_max_lifetime].contains(&Some(0u64))
+        {
+            return Err("Timeouts must be positive".into());
+        }
+
+        if cfg.tcp_buffer_size == 0usize || cfg.backlog == 0u32 {
+            return Err("TCP buffer size & backlog must be positive".into());
+        }
+
+        let udp_defaults = Settings {
+            connect_timeout: Duration::from_secs(cfg.connect_timeout),
+            idle_timeout: Some(Duration::from_secs(cfg.udp_idle_timeout)),
+            reply_timeout: cfg.udp_reply_timeout.map(Duration::from_secs),
+            max_lifetime: None,
+            buffer_size: cfg.udp_buffer_size,
+            socket_options: SocketOptions::default(),
+            spoof_source: false,
+            mark: cfg.upstream_mark.map(|m| m.0),
+            bind_address: None,
+            interface: None,
+            proxy_protocol: None,
+        };
+        let tcp_defaults = Settings {
+            connect_timeout: Duration::from_secs(cfg.connect_timeout),
+            idle_timeout: cfg.tcp_idle_timeout.map(Duration::from_secs),
+            reply_timeout: None,
+            max_lifetime: cfg.tcp_max_lifetime.map(Duration::from_secs),
+            buffer_size: cfg.tcp_buffer_size,
+            socket_options: SocketOptions::default(),
+            spoof_source: false,
+            mark: cfg.upstream_mark.map(|m| m.0),
+            bind_address: None,
+            interface: None,
+            proxy_protocol: None,
+        };
+
+        let main = Some(&cfg.main).filter(|m| m.is_enabled());
+        for (i, l) in main.into_iter().chain(cfg.listeners.iter()).enumerate() {
+            let name = match (&l.name, main.is_some() && i == 0usize) {
+                (Some(n), _) => n.clone(),
+                (None, true) => "default".into(),
+                (None, false) => format!("#{i}"),
+            };
+
+            if l.listen.is_empty() {
+                return Err(format!("Listener {name} needs at least one listen IP"));
+            }
+
+            let mut listen = Vec::with_capacity(l.listen.len());
+            for ip in &l.listen {
+                if !listen.contains(ip) {
+                    listen.push(*ip);
+                }
+            }
+
+            let addrs = |port: u16| listen.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+            let default = match l.default_action {
+                DefaultAction::Drop => None,
+                DefaultAction::Passthrough => Some(Target::Passthrough),
+            };
+
+            match (l.udp_port.or(l.port), l.udp.is_empty()) {
+                (Some(port), _) => {
+                    let rules = sorted_rules(&l.udp, &udp_defaults)?;
+                    if let Some(r) = rules
+                        .iter()
+                        .find(|r| r.settings.buffer_size > MAX_UDP_PAYLOAD)
+                    {
+                        return Err(format!(
+                            "Buffer size of the UDP rule for port {} exceeds {MAX_UDP_PAYLOAD} bytes",
+                            r.orig_port
+                        ));
+                    }
+
+                    if let Some(r) = rules
+                        .iter()
+                        .find(|r| r.settings.proxy_protocol == Some(ProxyProtocol::V1))
+                    {
+                        return Err(format!("UDP rule for port {} can only send PROXY protocol v2 headers", r.orig_port));
+                    }
+
+                    if let Some(r) = rules.iter().find(|r| r.limit.is_some()) {
+                        return Err(format!(
+                            "UDP rule for port {} can't have max_connections, UDP sessions being capped by backlog",
+                            r.orig_port
+                        ));
+                    }
+
+                    check_spoofing_families(&name, &rules, &listen)?;
+
+                    udp_listeners.push(Arc::new(Listener {
+                        name: name.clone(),
+                        addrs: addrs(port),
+                        map: UdpMap(rules, default.clone(), udp_defaults),
+                        proxy_from: None,
+                    }));
+                },
+                (None, false) => return Err(format!("Listener {name} has UDP rules but no UDP port")),
+                (None, true) => {},
+            };
+
+            let proxy_from = match (l.accept_proxy, l.proxy_from.is_empty()) {
+                (true, true) => return Err(format!("Listener {name} accepts the PROXY protocol but trusts no proxy_from peer")),
+                (true, false) => Some(l.proxy_from.clone()),
+                (false, true) => None,
+                (false, false) => return Err(format!("Listener {name} has proxy_from peers but doesn't accept the PROXY protocol")),
+            };
+
+            match (l.tcp_port.or(l.port), l.tcp.is_empty()) {
+                (Some(port), _) => {
+                    let rules = sorted_rules(&l.tcp, &tcp_defaults)?;
+                    check_spoofing_families(&name, &rules, &listen)?;
+
+                    // a forged header would otherwise relay to or spoof any address
+                    if proxy_from.is_some() {
+                        if default == Some(Target::Passthrough) {
+                            return Err(format!(
+                                "Listener {name} accepts the PROXY protocol so its default action can't be passthrough"
+                            ));
+                        }
+
+                        if let Some(r) = rules
+                            .iter()
+                            .find(|r| r.target == Target::Passthrough || r.settings.spoof_source)
+                        {
+                            return Err(format!(
+                                "Listener {name} accepts the PROXY protocol so its rule for port {} can't be passthrough or spoof the source",
+                                r.orig_port
+                            ));
+                        }
+                    }
+
+                    tcp_listeners.push(Arc::new(Listener {
+                        name: name.clone(),
+                        addrs: addrs(port),
+                        map: TcpMap(rules, default, tcp_defaults),
+                        proxy_from,
+                    }));
+                },
+                (None, false) => return Err(format!("Listener {name} has TCP rules but no TCP port")),
+                (None, true) if proxy_from.is_some() => return Err(format!("Listener {name} accepts the PROXY protocol but has no TCP port")),
+                (None, true) => {},
+            };
+
+            if l.udp_port.or(l.tcp_port).or(l.port).is_none() {
+                return Err(format!("Listener {name} has neither a TCP nor a UDP port"));
+            }
+        }
+
+        if udp_listeners.is_empty() && tcp_listeners.is_empty() {
+            return Err("No listener configured".into());
         }
+
+        for (proto, addrs) in [("UDP", bindings(&udp_listeners).0), ("TCP", bindings(&tcp_listeners).0)] {
+            if let Some(addr) = addrs
+                .iter()
+                .enumerate()
+                .find_map(|(i, a)| addrs[..i].contains(a).then_some(a))
+            {
+                return Err(format!("{addr} is bound by more than one {proto} listener"));
+            }
+        }
+
+        let workers = match cfg.workers {
+            Some(0usize) => return Err("Workers must be positive".into()),
+            Some(n) => n,
+            None => thread::available_parallelism().map_or(1usize, |n| n.get()),
+        };
+
+        Ok(Self {
+            udp_listeners,
+            tcp_listeners,
+            udp_buffer_size: cfg.udp_buffer_size,
+            drain_timeout: Duration::from_secs(cfg.drain_timeout),
+            backlog: cfg.backlog,
+            nameservers: cfg.nameservers.clone(),
+            tcp_splice: cfg.tcp_splice,
+            workers,
+            udp_limit: Arc::new(ConnLimit::new(cfg.backlog as usize).map_err(|e| format!("UDP - {e}"))?),
+            tcp_limit: cfg
+                .tcp_max_connections
+                .map(ConnLimit::new)
+                .transpose()
+                .map_err(|e| format!("TCP - {e}"))?
+                .map(Arc::new),
+            tcp_overload: cfg.tcp_overload,
+        })
     }
 }
 
 pub(crate) trait ForwarderMap {
-    fn get(&self, k: &u16) -> Option<&(Ipv4Addr, u16)>;
+    fn rules(&self) -> &[Rule];
+
+    fn default_target(&self) -> Option<&Target>;
+
+    /// Settings of the traffic matching no rule
+    fn default_settings(&self) -> &Settings;
+
+    /// Upstream pools of all the rules
+    fn pools(&self) -> Vec<&Arc<UpstreamPool>> {
+        self.rules()
+            .iter()
+            .filter_map(|r| match &r.target {
+                Target::Upstream(pool) => Some(pool),
+                Target::Passthrough => None,
+            })
+            .collect()
+    }
+
+    /// Route of the most specific rule matching the original destination, else the default one
+    fn lookup(&self, dst: &SocketAddr) -> Option<Route<'_>> {
+        match self.rules().iter().find(|r| r.matches(dst)) {
+            Some(r) => Some(Route {
+                target: &r.target,
+                limit: r.limit.as_ref(),
+                settings: r.settings,
+            }),
+            None => self.default_target().map(|target| Route {
+                target,
+                limit: None,
+                settings: *self.default_settings(),
+            }),
+        }
+    }
 }
 
-#[derive(Clone, PartialEq, Eq)]
-pub(crate) struct TcpMap(HashMap<u16, (Ipv4Addr, u16)>);
+/// Where traffic to an original destination is sent & how its sessions behave
+pub(crate) struct Route<'a> {
+    pub(crate) target: &'a Target,
+    pub(crate) limit: Option<&'a Arc<ConnLimit>>,
+    pub(crate) settings: Settings,
+}
+
+#[derive(PartialEq, Eq)]
+pub(crate) struct TcpMap(Vec<Rule>, Option<Target>, Settings);
 
 impl ForwarderMap for TcpMap {
-    fn get(&self, k: &u16) -> Option<&(Ipv4Addr, u16)> {
-        self.0.get(k)
+    fn rules(&self) -> &[Rule] {
+        &self.0
+    }
+
+    fn default_target(&self) -> Option<&Target> {
+        self.1.as_ref()
+    }
+
+    fn default_settings(&self) -> &Settings {
+        &self.2
     }
 }
 
-#[derive(Clone, PartialEq, Eq)]
-pub(crate) struct UdpMap(HashMap<u16, (Ipv4Addr, u16)>);
+#[derive(PartialEq, Eq)]
+pub(crate) struct UdpMap(Vec<Rule>, Option<Target>, Settings);
 
 impl ForwarderMap for UdpMap {
-    fn get(&self, k: &u16) -> Option<&(Ipv4Addr, u16)> {
-        self.0.get(k)
+    fn rules(&self) -> &[Rule] {
+        &self.0
+    }
+
+    fn default_target(&self) -> Option<&Target> {
+        self.1.as_ref()
+    }
+
+    fn default_settings(&self) -> &Settings {
+        &self.2
     }
 }
 
@@ -132,6 +1457,35 @@ pub(crate) enum Actions {
     PANICKED,
 }
 
+/// Fixtures shared by the tests of other modules & the benchmarks
+#[cfg(any(test, feature = "bench"))]
+pub(crate) mod fixtures {
+    use super::*;
+
+    /// Session settings from the configuration defaults, with no session timeout
+    pub(crate) fn default_settings() -> Settings {
+        Settings {
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT),
+            idle_timeout: None,
+            reply_timeout: None,
+            max_lifetime: None,
+            buffer_size: DEFAULT_TCP_BUFFER_SIZE,
+            socket_options: SocketOptions::default(),
+            spoof_source: false,
+            mark: None,
+            bind_address: None,
+            interface: None,
+            proxy_protocol: None,
+        }
+    }
+
+    /// Runtime configuration of a JSON configuration, which must be valid
+    #[cfg(test)]
+    pub(crate) fn runtime_configs(json: &str) -> RuntimeConfigs {
+        RuntimeConfigs::try_from(&serde_json::from_str::<Configs>(json).unwrap()).unwrap()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #![allow(non_snake_case)]
@@ -190,46 +1544,768 @@ mod tests {
     }
 
     #[test]
-    fn test_RuntimeConfigs_from() {
-        let ip = Ipv4Addr::from([10, 0, 0, 1]);
+    fn test_RuntimeConfigs_try_from() {
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let listen_ip = IpAddr::from([127u8, 0u8, 0u8, 2u8]);
         let inner_port = 53u16;
         let outer_port = 8080u16;
+        let port_range = PortRange::new(inner_port, inner_port).unwrap();
+        let forwarder = |upstream_ip: Option<IpAddr>, upstream_port: Option<u16>, action: RuleAction| Forwarders {
+            upstream: upstream_ip.map(Host::Ip),
+            upstream_port,
+            upstreams: Vec::new(),
+            strategy: Strategy::RoundRobin,
+            health_check: None,
+            connect_retry: None,
+            max_connections: None,
+            connect_timeout: None,
+            idle_timeout: None,
+            reply_timeout: None,
+            max_lifetime: None,
+            buffer_size: None,
+            socket_options: None,
+            spoof_source: false,
+            mark: None,
+            bind_address: None,
+            interface: None,
+            proxy_protocol: None,
+            orig_ip: None,
+            orig_port: port_range,
+            action,
+        };
+
+        let mut configs = Configs {
+            main: ListenerConfigs {
+                listen: vec![listen_ip, listen_ip],
+                port: Some(outer_port),
+                udp: [forwarder(Some(ip), Some(inner_port), RuleAction::Forward)].into(),
+                tcp: [forwarder(None, None, RuleAction::Passthrough)].into(),
+                default_action: DefaultAction::Passthrough,
+                ..Default::default()
+            },
+            listeners: Vec::new(),
+            udp_idle_timeout: 45u64,
+            udp_reply_timeout: Some(5u64),
+            connect_timeout: 3u64,
+            tcp_idle_timeout: Some(300u64),
+            tcp_max_lifetime: None,
+            tcp_buffer_size: 8192usize,
+            drain_timeout: 10u64,
+            backlog: 200u32,
+            udp_buffer_size: 9000usize,
+            nameservers: Vec::new(),
+            tcp_splice: true,
+            workers: Some(4usize),
+            upstream_mark: Some(Fwmark(0x3002u32)),
+            tcp_max_connections: Some(1000usize),
+            tcp_overload: OverloadAction::Reset,
+        };
+
+        let runtime_configs = RuntimeConfigs::try_from(&configs).unwrap();
+        assert_eq!(1usize, runtime_configs.udp_listeners.len());
+        assert_eq!(1usize, runtime_configs.tcp_listeners.len());
+
+        let (udp, tcp) = (&runtime_configs.udp_listeners[0], &runtime_configs.tcp_listeners[0]);
+        assert_eq!("default", udp.name);
+        assert_eq!(vec![SocketAddr::new(listen_ip, outer_port)], udp.addrs);
+        assert_eq!(vec![SocketAddr::new(listen_ip, outer_port)], tcp.addrs);
+        assert_eq!(
+            vec![Rule {
+                orig_ip: None,
+                orig_port: port_range,
+                target: Target::Upstream(Arc::new(
+                    UpstreamPool::new(
+                        Strategy::RoundRobin,
+                        &[Member {
+                            host: Host::Ip(ip),
+                            port: inner_port,
+                            weight: 1u32,
+                        }],
+                        None,
+                        None,
+                    )
+                    .unwrap()
+                )),
+                limit: None,
+                settings: udp.map.2,
+            }],
+            udp.map.0
+        );
+        assert_eq!(
+            vec![Rule {
+                orig_ip: None,
+                orig_port: port_range,
+                target: Target::Passthrough,
+                limit: None,
+                settings: tcp.map.2,
+            }],
+            tcp.map.0
+        );
+        assert_eq!(Some(Target::Passthrough), udp.map.1);
+        assert_eq!(Some(Target::Passthrough), tcp.map.1);
+        assert_eq!(
+            Settings {
+                connect_timeout: Duration::from_secs(3u64),
+                idle_timeout: Some(Duration::from_secs(45u64)),
+                reply_timeout: Some(Duration::from_secs(5u64)),
+                max_lifetime: None,
+                buffer_size: 9000usize,
+                socket_options: SocketOptions::default(),
+                spoof_source: false,
+                mark: Some(0x3002u32),
+                bind_address: None,
+                interface: None,
+                proxy_protocol: None,
+            },
+            udp.map.2
+        );
+        assert_eq!(
+            Settings {
+                connect_timeout: Duration::from_secs(3u64),
+                idle_timeout: Some(Duration::from_secs(300u64)),
+                reply_timeout: None,
+                max_lifetime: None,
+                buffer_size: 8192usize,
+                socket_options: SocketOptions::default(),
+                spoof_source: false,
+                mark: Some(0x3002u32),
+                bind_address: None,
+                interface: None,
+                proxy_protocol: None,
+            },
+            tcp.map.2
+        );
+        assert_eq!(9000usize, runtime_configs.udp_buffer_size);
+        assert_eq!(Duration::from_secs(10u64), runtime_configs.drain_timeout);
+        assert_eq!(200u32, runtime_configs.backlog);
+        assert!(runtime_configs.tcp_splice);
+        assert_eq!(4usize, runtime_configs.workers);
+        assert_eq!(Some(1000usize), runtime_configs.tcp_limit.as_ref().map(|l| l.max()));
+        assert_eq!(OverloadAction::Reset, runtime_configs.tcp_overload);
+
+        configs.udp_buffer_size = MAX_UDP_PAYLOAD + 1usize;
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.udp_buffer_size = 0usize;
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.udp_buffer_size = 9000usize;
+
+        configs.connect_timeout = 0u64;
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.connect_timeout = 3u64;
+        configs.backlog = 0u32;
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.backlog = 200u32;
+        configs.tcp_max_lifetime = Some(0u64);
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.tcp_max_lifetime = None;
+
+        // per rule overrides
+        let overridden = |idle_timeout: Option<u64>, buffer_size: Option<usize>| Forwarders {
+            idle_timeout,
+            buffer_size,
+            ..forwarder(Some(ip), Some(inner_port), RuleAction::Forward)
+        };
+        configs.main.udp = [overridden(Some(10u64), Some(512usize))].into();
+        let settings = RuntimeConfigs::try_from(&configs).unwrap().udp_listeners[0]
+            .map
+            .0[0]
+            .settings;
+        assert_eq!(Some(Duration::from_secs(10u64)), settings.idle_timeout);
+        assert_eq!(Some(Duration::from_secs(5u64)), settings.reply_timeout);
+        assert_eq!(512usize, settings.buffer_size);
+
+        assert!(
+            RuntimeConfigs::try_from(&configs)
+                .unwrap()
+                .spoofing_addrs()
+                .is_empty()
+        );
+
+        let spoofing = Forwarders {
+            spoof_source: true,
+            ..forwarder(None, None, RuleAction::Passthrough)
+        };
+        configs.main.tcp = [spoofing].into();
+        let runtime_configs = RuntimeConfigs::try_from(&configs).unwrap();
+        assert!(
+            runtime_configs.tcp_listeners[0].map.0[0]
+                .settings
+                .spoof_source
+        );
+        assert_eq!(vec![SocketAddr::new(listen_ip, outer_port)], runtime_configs.spoofing_addrs());
+
+        // spoofed binds need upstreams of the family of the clients
+        let spoofing_to = |upstream: Host, orig_ip: Option<&str>| Forwarders {
+            spoof_source: true,
+            upstream: Some(upstream),
+            orig_ip: orig_ip.map(|net| IpNet::from_str(net).unwrap()),
+            ..forwarder(None, Some(inner_port), RuleAction::Forward)
+        };
+        configs.main.tcp = [spoofing_to(Host::Ip(ip), None)].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_ok());
+        configs.main.tcp = [spoofing_to(Host::Ip(IpAddr::from(Ipv6Addr::LOCALHOST)), None)].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.main.tcp = [spoofing_to(Host::Name("web.lan".into()), None)].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.main.listen = vec![listen_ip, IpAddr::from(Ipv6Addr::LOCALHOST)];
+        configs.main.tcp = [spoofing_to(Host::Ip(ip), None)].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.main.tcp = [spoofing_to(Host::Ip(ip), Some("10.0.0.0/8"))].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_ok());
+        configs.main.listen = vec![listen_ip, listen_ip];
+
+        let spoofing_bound = Forwarders {
+            spoof_source: true,
+            bind_address: Some(IpAddr::from([192u8, 168u8, 1u8, 2u8])),
+            ..forwarder(None, None, RuleAction::Passthrough)
+        };
+        configs.main.tcp = [spoofing_bound].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+
+        let bound = Forwarders {
+            bind_address: Some(IpAddr::from([192u8, 168u8, 1u8, 2u8])),
+            interface: Some(Interface::try_from(String::from("eth1")).unwrap()),
+            ..forwarder(None, None, RuleAction::Passthrough)
+        };
+        configs.main.tcp = [bound].into();
+        let settings = RuntimeConfigs::try_from(&configs).unwrap().tcp_listeners[0]
+            .map
+            .0[0]
+            .settings;
+        assert_eq!(Some(IpAddr::from([192u8, 168u8, 1u8, 2u8])), settings.bind_address);
+        assert_eq!(b"eth1", settings.interface.unwrap().as_bytes());
+        configs.main.tcp = [forwarder(None, None, RuleAction::Passthrough)].into();
+
+        configs.main.udp = [overridden(None, Some(MAX_UDP_PAYLOAD + 1usize))].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.main.udp = [overridden(Some(0u64), None)].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+
+        let proxied = |version: ProxyProtocol| Forwarders {
+            proxy_protocol: Some(version),
+            ..forwarder(Some(ip), Some(inner_port), RuleAction::Forward)
+        };
+        configs.main.udp = [proxied(ProxyProtocol::V1)].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.main.udp = [Forwarders {
+            max_connections: Some(10usize),
+            ..forwarder(Some(ip), Some(inner_port), RuleAction::Forward)
+        }]
+        .into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.main.udp = [proxied(ProxyProtocol::V2)].into();
+        assert_eq!(
+            Some(ProxyProtocol::V2),
+            RuntimeConfigs::try_from(&configs).unwrap().udp_listeners[0]
+                .map
+                .0[0]
+                .settings
+                .proxy_protocol
+        );
+        configs.main.udp = [forwarder(Some(ip), Some(inner_port), RuleAction::Forward)].into();
+
+        configs.main.default_action = DefaultAction::Drop;
+        configs.main.tcp_port = Some(outer_port + 1u16);
+        let runtime_configs = RuntimeConfigs::try_from(&configs).unwrap();
+        assert_eq!(None, runtime_configs.udp_listeners[0].map.1);
+        assert_eq!(None, runtime_configs.tcp_listeners[0].map.1);
+        assert_eq!(
+            vec![SocketAddr::new(listen_ip, outer_port + 1u16)],
+            runtime_configs.tcp_listeners[0].addrs
+        );
+
+        // extra listeners
+        configs.listeners = vec![ListenerConfigs {
+            name: Some("dns".into()),
+            listen: vec![listen_ip],
+            udp_port: Some(outer_port + 2u16),
+            ..Default::default()
+        }];
+        let runtime_configs = RuntimeConfigs::try_from(&configs).unwrap();
+        assert_eq!(2usize, runtime_configs.udp_listeners.len());
+        assert_eq!(1usize, runtime_configs.tcp_listeners.len());
+        assert_eq!("dns", runtime_configs.udp_listeners[1].name);
+        assert_eq!(None, runtime_configs.tcp_listeners[0].proxy_from);
+        assert_eq!(
+            vec![SocketAddr::new(listen_ip, outer_port), SocketAddr::new(listen_ip, outer_port + 2u16)],
+            bindings(&runtime_configs.udp_listeners).0
+        );
+
+        // PROXY protocol over TCP only, from trusted peers
+        let trusted = vec![IpNet::from_str("10.0.0.0/24").unwrap()];
+        configs.listeners[0].accept_proxy = true;
+        configs.listeners[0].tcp_port = Some(outer_port + 3u16);
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.listeners[0].proxy_from = trusted.clone();
+        let runtime_configs = RuntimeConfigs::try_from(&configs).unwrap();
+        assert_eq!(Some(trusted.clone()), runtime_configs.tcp_listeners[1].proxy_from);
+        assert_eq!(None, runtime_configs.udp_listeners[1].proxy_from);
+        configs.listeners[0].tcp_port = None;
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.listeners[0].tcp_port = Some(outer_port + 3u16);
+
+        // no relaying to or spoofing any address
+        configs.listeners[0].default_action = DefaultAction::Passthrough;
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.listeners[0].default_action = DefaultAction::Drop;
+        configs.listeners[0].tcp = [forwarder(None, None, RuleAction::Passthrough)].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.listeners[0].tcp = [Forwarders {
+            spoof_source: true,
+            ..forwarder(Some(ip), Some(inner_port), RuleAction::Forward)
+        }]
+        .into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.listeners[0].tcp = [forwarder(Some(ip), Some(inner_port), RuleAction::Forward)].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_ok());
+
+        configs.listeners[0].accept_proxy = false;
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.listeners[0].proxy_from.clear();
+        configs.listeners[0].tcp.clear();
+        configs.listeners[0].tcp_port = None;
+
+        // same address bound twice
+        configs.listeners[0].udp_port = Some(outer_port);
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+
+        // no port
+        configs.listeners[0].udp_port = None;
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+
+        // rules without a port for their protocol
+        configs.listeners[0].tcp_port = Some(outer_port + 2u16);
+        configs.listeners[0].udp = [forwarder(Some(ip), Some(inner_port), RuleAction::Forward)].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+        configs.listeners.clear();
+
+        configs.main.udp = [forwarder(Some(ip), None, RuleAction::Forward)].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+
+        configs.main.udp = [forwarder(Some(ip), Some(inner_port), RuleAction::Passthrough)].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+
+        // single upstream & pool together
+        let mut pooled = forwarder(Some(ip), Some(inner_port), RuleAction::Forward);
+        pooled.upstreams = vec![UpstreamConfigs {
+            host: Host::Ip(ip),
+            port: inner_port,
+            weight: 1u32,
+        }];
+        configs.main.udp = [pooled].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+
+        // zero weight
+        let mut pooled = forwarder(None, None, RuleAction::Forward);
+        pooled.upstreams = vec![UpstreamConfigs {
+            host: Host::Ip(ip),
+            port: inner_port,
+            weight: 0u32,
+        }];
+        configs.main.udp = [pooled].into();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+
+        configs.main.udp = HashSet::new();
+        configs.main.listen = Vec::new();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+
+        // disabled main listener & no other listener
+        configs.main = ListenerConfigs::default();
+        assert!(RuntimeConfigs::try_from(&configs).is_err());
+    }
+
+    #[test]
+    fn test_Configs_deserialize() {
+        let configs: Configs = serde_json::from_str(
+            r#"{
+                "port": 8080,
+                "udp": [{ "upstream_ip": "10.0.0.1", "upstream_port": 53, "orig_port": 53 }],
+                "tcp": [],
+                "listeners": [{
+                    "name": "web",
+                    "listen": ["::1"],
+                    "tcp_port": 8443,
+                    "tcp": [
+                        { "orig_port": 443, "action": "passthrough" },
+                        {
+                            "orig_port": 80,
+                            "upstreams": [{ "ip": "::2", "port": 80, "weight": 2 }, { "host": "web.lan", "port": 80 }],
+                            "strategy": "least_connections"
+                        }
+                    ]
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(configs.main.is_enabled());
+        assert_eq!(Some(8080u16), configs.main.port);
+        assert_eq!(DEFAULT_LISTEN_IPS.to_vec(), configs.main.listen);
+        assert_eq!(1usize, configs.main.udp.len());
+        assert_eq!(1usize, configs.listeners.len());
+        assert_eq!(Some("web".to_string()), configs.listeners[0].name);
+        assert_eq!(Some(8443u16), configs.listeners[0].tcp_port);
+        assert_eq!(DEFAULT_UDP_IDLE_TIMEOUT, configs.udp_idle_timeout);
+        assert_eq!(MAX_UDP_PAYLOAD, configs.udp_buffer_size);
+        assert_eq!(None, configs.udp_reply_timeout);
+        assert_eq!(None, configs.tcp_idle_timeout);
+        assert_eq!(None, configs.tcp_max_lifetime);
+        assert_eq!(DEFAULT_CONNECT_TIMEOUT, configs.connect_timeout);
+        assert_eq!(DEFAULT_TCP_BUFFER_SIZE, configs.tcp_buffer_size);
+        assert_eq!(DEFAULT_DRAIN_TIMEOUT, configs.drain_timeout);
+        assert_eq!(DEFAULT_BACKLOG, configs.backlog);
+
+        let runtime_configs = RuntimeConfigs::try_from(&configs).unwrap();
+        assert_eq!(1usize, runtime_configs.udp_listeners.len());
+        assert_eq!(2usize, runtime_configs.tcp_listeners.len());
+        assert_eq!(vec![SocketAddr::from_str("[::1]:8443").unwrap()], runtime_configs.tcp_listeners[1].addrs);
+
+        let web = configs.listeners[0]
+            .tcp
+            .iter()
+            .find(|f| !f.upstreams.is_empty())
+            .unwrap();
+        assert_eq!(Strategy::LeastConnections, web.strategy);
+        assert_eq!(2u32, web.upstreams[0].weight);
+        assert_eq!(1u32, web.upstreams[1].weight);
+        assert_eq!(Host::Ip(IpAddr::from_str("::2").unwrap()), web.upstreams[0].host);
+        assert_eq!(Host::Name("web.lan".into()), web.upstreams[1].host);
+        assert!(configs.nameservers.is_empty());
+        assert_eq!(None, configs.workers);
+        assert!(runtime_configs.workers >= 1usize);
+    }
+
+    #[test]
+    fn test_RuntimeConfigs_carry_limits() {
+        let runtime_configs = |max: usize| {
+            let configs: Configs = serde_json::from_str(&format!(
+                r#"{{
+                    "tcp_port": 8080,
+                    "tcp_max_connections": {max},
+                    "tcp": [{{ "upstream_ip": "10.0.0.1", "upstream_port": 80, "orig_port": 80, "max_connections": {max} }}]
+                }}"#
+            ))
+            .unwrap();
+            RuntimeConfigs::try_from(&configs).unwrap()
+        };
+        let rule_limit = |c: &RuntimeConfigs| c.tcp_listeners[0].map.rules()[0].limit.clone().unwrap();
+
+        let current = runtime_configs(2usize);
+        let _open = (
+            current.tcp_limit.as_ref().unwrap().try_acquire().unwrap(),
+            rule_limit(&current).try_acquire().unwrap(),
+        );
+
+        let mut new = runtime_configs(1usize);
+        new.carry_limits(&current);
+        assert!(Arc::ptr_eq(current.tcp_limit.as_ref().unwrap(), new.tcp_limit.as_ref().unwrap()));
+        assert!(Arc::ptr_eq(&current.udp_limit, &new.udp_limit));
+        assert!(Arc::ptr_eq(&rule_limit(&current), &rule_limit(&new)));
+
+        // the connection still open counts against the lowered maximum
+        assert_eq!(1usize, rule_limit(&new).max());
+        assert!(rule_limit(&new).try_acquire().is_err());
+        assert_eq!(
+            vec![
+                "UDP session".to_string(),
+                "TCP connection".to_string(),
+                "default rule for port 80 connection".to_string()
+            ],
+            new.limits()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_RuntimeConfigs_carry_resolved() {
+        let runtime_configs = || {
+            let configs: Configs = serde_json::from_str(
+                r#"{
+                    "tcp_port": 8080,
+                    "tcp": [{ "upstreams": [{ "host": "web.lan", "port": 80 }], "orig_port": 80 }]
+                }"#,
+            )
+            .unwrap();
+            RuntimeConfigs::try_from(&configs).unwrap()
+        };
+        let ip = IpAddr::from([10u8, 0u8, 0u8, 1u8]);
+
+        let current = runtime_configs();
+        current.pools()[0].set_resolved("web.lan", &[ip]);
+
+        let new = runtime_configs();
+        assert!(new.pools()[0].upstreams().is_empty());
+        new.carry_resolved(&current);
+        assert_eq!(Some(vec![ip]), new.pools()[0].resolved("web.lan"));
+        assert_eq!(SocketAddr::new(ip, 80u16), new.pools()[0].upstreams()[0].addr);
+    }
+
+    #[test]
+    fn test_RuntimeConfigs_try_from_duplicate_rules() {
+        let configs = |tcp: &str| {
+            let configs: Configs = serde_json::from_str(&format!(r#"{{ "tcp_port": 8080, "tcp": [{tcp}] }}"#)).unwrap();
+            RuntimeConfigs::try_from(&configs).map(|_| ())
+        };
+
+        assert_eq!(
+            Err("Rule forwarding to 10.0.0.1:80 & rule passing through both match port 80".to_string()),
+            configs(
+                r#"{ "upstream_ip": "10.0.0.1", "upstream_port": 80, "orig_port": 80 },
+                { "orig_port": 80, "action": "passthrough" }"#
+            )
+        );
+        assert_eq!(
+            Err("Rule forwarding to 10.0.0.1:80 & rule forwarding to web.lan:80, 10.0.0.2:80 both match 10.0.0.0/8 port 80-81".to_string()),
+            configs(
+                r#"{ "upstream_ip": "10.0.0.1", "upstream_port": 80, "orig_ip": "10.0.0.0/8", "orig_port": "80-81" },
+                { "upstreams": [{ "host": "web.lan", "port": 80 }, { "ip": "10.0.0.2", "port": 80 }], "orig_ip": "10.0.0.0/8", "orig_port": "80-81" }"#
+            )
+        );
+
+        // the same ports with another destination prefix or the reverse are fine
+        assert_eq!(
+            Ok(()),
+            configs(
+                r#"{ "upstream_ip": "10.0.0.1", "upstream_port": 80, "orig_ip": "10.0.0.0/8", "orig_port": 80 },
+                { "orig_port": 80, "action": "passthrough" }"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_HealthCheck_try_from() {
+        let mut cfg: HealthCheckConfigs = serde_json::from_str(r#"{ "payload": "00ff1A", "expect": "1a" }"#).unwrap();
+        let check = HealthCheck::try_from(&cfg).unwrap();
+        assert_eq!(Duration::from_secs(DEFAULT_HEALTH_INTERVAL), check.interval);
+        assert_eq!(Duration::from_secs(DEFAULT_HEALTH_TIMEOUT), check.timeout);
+        assert_eq!(DEFAULT_HEALTH_RISE, check.rise);
+        assert_eq!(DEFAULT_HEALTH_FALL, check.fall);
+        assert_eq!(vec![0x00u8, 0xffu8, 0x1au8], check.payload);
+        assert_eq!(Some(vec![0x1au8]), check.expect);
+
+        cfg.payload = None;
+        assert!(HealthCheck::try_from(&cfg).unwrap().payload.is_empty());
+
+        cfg.expect = Some("abc".into());
+        assert!(HealthCheck::try_from(&cfg).is_err());
+        cfg.expect = Some("zz".into());
+        assert!(HealthCheck::try_from(&cfg).is_err());
+        cfg.expect = Some(String::new());
+        assert!(HealthCheck::try_from(&cfg).is_err());
+        cfg.expect = None;
+
+        cfg.fall = 0u32;
+        assert!(HealthCheck::try_from(&cfg).is_err());
+        cfg.fall = 1u32;
+        cfg.interval = 0u64;
+        assert!(HealthCheck::try_from(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_SocketOptions_try_from() {
+        let mut cfg: SocketOptionsConfigs = serde_json::from_str(
+            r#"{ "keepalive": { "idle": 60, "count": 5 }, "user_timeout": 30000, "nodelay": true, "send_buffer_size": 262144 }"#,
+        )
+        .unwrap();
+        let opts = SocketOptions::try_from(&cfg).unwrap();
+        assert_eq!(
+            Some(Keepalive {
+                idle: Some(Duration::from_secs(60u64)),
+                interval: None,
+                count: Some(5u32),
+            }),
+            opts.keepalive
+        );
+        assert_eq!(Some(Duration::from_secs(30u64)), opts.user_timeout);
+        assert_eq!(Some(true), opts.nodelay);
+        assert_eq!(Some(262144usize), opts.send_buffer_size);
+        assert_eq!(None, opts.recv_buffer_size);
+
+        let empty: SocketOptionsConfigs = serde_json::from_str(r#"{ "keepalive": {} }"#).unwrap();
+        assert_eq!(Some(Keepalive::default()), SocketOptions::try_from(&empty).unwrap().keepalive);
+
+        cfg.keepalive = Some(KeepaliveConfigs {
+            count: Some(0u32),
+            ..Default::default()
+        });
+        assert!(SocketOptions::try_from(&cfg).is_err());
+        cfg.keepalive = None;
+        cfg.user_timeout = Some(0u64);
+        assert!(SocketOptions::try_from(&cfg).is_err());
+        cfg.user_timeout = None;
+        cfg.recv_buffer_size = Some(0usize);
+        assert!(SocketOptions::try_from(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_IpNet_from_str() {
+        let net = IpNet::from_str("10.1.2.3/8").unwrap();
+        assert_eq!(IpAddr::from([10u8, 0u8, 0u8, 0u8]), net.addr);
+        assert_eq!(8u8, net.prefix_len);
+        assert_eq!("10.0.0.0/8", net.to_string());
+
+        let host = IpNet::from_str("1.1.1.1").unwrap();
+        assert_eq!(32u8, host.prefix_len);
+
+        let net6 = IpNet::from_str("fd00::1/16").unwrap();
+        assert_eq!("fd00::/16", net6.to_string());
+        assert_eq!(128u8, IpNet::from_str("::1").unwrap().prefix_len);
+        assert_eq!("0.0.0.0/0", IpNet::from_str("10.0.0.1/0").unwrap().to_string());
+
+        assert!(IpNet::from_str("10.0.0.0/33").is_err());
+        assert!(IpNet::from_str("fd00::/129").is_err());
+        assert!(IpNet::from_str("10.0.0/8").is_err());
+        assert!(IpNet::from_str("10.0.0.0/x").is_err());
+    }
+
+    #[test]
+    fn test_IpNet_contains() {
+        let net = IpNet::from_str("192.168.0.0/16").unwrap();
+        assert!(net.contains(&IpAddr::from([192u8, 168u8, 10u8, 1u8])));
+        assert!(!net.contains(&IpAddr::from([192u8, 169u8, 0u8, 1u8])));
+        assert!(!net.contains(&IpAddr::from_str("::ffff:192.168.10.1").unwrap()));
+
+        let any = IpNet::from_str("0.0.0.0/0").unwrap();
+        assert!(any.contains(&IpAddr::from([8u8, 8u8, 8u8, 8u8])));
+        assert!(!any.contains(&IpAddr::from_str("2001:db8::1").unwrap()));
+
+        let net6 = IpNet::from_str("2001:db8::/32").unwrap();
+        assert!(net6.contains(&IpAddr::from_str("2001:db8:1::1").unwrap()));
+        assert!(!net6.contains(&IpAddr::from_str("2001:db9::1").unwrap()));
+    }
+
+    #[test]
+    fn test_Fwmark_deserialize() {
+        assert_eq!(Fwmark(0x3001u32), serde_json::from_str::<Fwmark>("12289").unwrap());
+        assert_eq!(Fwmark(0x3001u32), serde_json::from_str::<Fwmark>("\"0x3001\"").unwrap());
+        assert_eq!(Fwmark(0xffu32), serde_json::from_str::<Fwmark>("\"0XFF\"").unwrap());
+        assert!(serde_json::from_str::<Fwmark>("0").is_err());
+        assert!(serde_json::from_str::<Fwmark>("\"0x0\"").is_err());
+        assert!(serde_json::from_str::<Fwmark>("\"3001\"").is_err());
+        assert!(serde_json::from_str::<Fwmark>("\"0xfg\"").is_err());
+        assert!(serde_json::from_str::<Fwmark>("-1").is_err());
+    }
+
+    #[test]
+    fn test_Interface_deserialize() {
+        let interface = serde_json::from_str::<Interface>("\"eth0.100\"").unwrap();
+        assert_eq!(b"eth0.100", interface.as_bytes());
+        assert_eq!("eth0.100", interface.to_string());
+
+        let longest = serde_json::from_str::<Interface>("\"abcdefghijklmno\"").unwrap();
+        assert_eq!(b"abcdefghijklmno", longest.as_bytes());
+
+        assert!(serde_json::from_str::<Interface>("\"\"").is_err());
+        assert!(serde_json::from_str::<Interface>("\"abcdefghijklmnop\"").is_err());
+        assert!(serde_json::from_str::<Interface>("\"eth 0\"").is_err());
+        assert!(serde_json::from_str::<Interface>("\"eth/0\"").is_err());
+    }
+
+    #[test]
+    fn test_PortRange_deserialize() {
+        let single: PortRange = serde_json::from_str("53").unwrap();
+        assert_eq!(PortRange::new(53u16, 53u16).unwrap(), single);
+        assert_eq!(1u32, single.size());
+
+        let range: PortRange = serde_json::from_str("\"8000-8999\"").unwrap();
+        assert_eq!(PortRange::new(8000u16, 8999u16).unwrap(), range);
+        assert_eq!(1000u32, range.size());
+        assert!(range.contains(8000u16));
+        assert!(range.contains(8999u16));
+        assert!(!range.contains(9000u16));
+
+        let quoted: PortRange = serde_json::from_str("\"443\"").unwrap();
+        assert_eq!(PortRange::new(443u16, 443u16).unwrap(), quoted);
+
+        assert!(serde_json::from_str::<PortRange>("\"9000-8000\"").is_err());
+        assert!(serde_json::from_str::<PortRange>("\"80-x\"").is_err());
+        assert!(serde_json::from_str::<PortRange>("70000").is_err());
+    }
+
+    #[test]
+    fn test_ForwarderMap_lookup() {
+        let forwarder = |orig_ip: Option<&str>, orig_port: (u16, u16), upstream_port: u16| Forwarders {
+            upstream: Some(Host::Ip(IpAddr::from([10u8, 0u8, 0u8, 1u8]))),
+            upstream_port: Some(upstream_port),
+            upstreams: Vec::new(),
+            strategy: Strategy::RoundRobin,
+            health_check: None,
+            connect_retry: None,
+            max_connections: None,
+            connect_timeout: None,
+            idle_timeout: None,
+            reply_timeout: None,
+            max_lifetime: None,
+            buffer_size: (upstream_port == 3u16).then_some(1024usize),
+            socket_options: None,
+            spoof_source: false,
+            mark: (upstream_port == 4u16).then_some(Fwmark(7u32)),
+            bind_address: None,
+            interface: None,
+            proxy_protocol: None,
+            orig_ip: orig_ip.map(|i| IpNet::from_str(i).unwrap()),
+            orig_port: PortRange::new(orig_port.0, orig_port.1).unwrap(),
+            action: RuleAction::Forward,
+        };
+        let dst = |s: &str| SocketAddr::from_str(s).unwrap();
+        let defaults = Settings {
+            connect_timeout: Duration::from_secs(2u64),
+            idle_timeout: None,
+            reply_timeout: None,
+            max_lifetime: None,
+            buffer_size: 4096usize,
+            socket_options: SocketOptions::default(),
+            spoof_source: false,
+            mark: None,
+            bind_address: None,
+            interface: None,
+            proxy_protocol: None,
+        };
+
+        let fwds = HashSet::from([
+            forwarder(None, (53u16, 53u16), 1u16),
+            forwarder(Some("8.8.0.0/16"), (53u16, 53u16), 2u16),
+            forwarder(Some("8.8.8.8"), (53u16, 53u16), 3u16),
+            forwarder(Some("8.8.8.8"), (1u16, 1024u16), 4u16),
+            forwarder(None, (1000u16, 2000u16), 5u16),
+            forwarder(Some("2001:db8::/32"), (53u16, 53u16), 6u16),
+        ]);
+        let upstream_port = |map: &dyn ForwarderMap, d: &str| {
+            map.lookup(&dst(d))
+                .and_then(|r| r.target.resolve(&dst(d), &dst(d)))
+                .map(|(addr, _)| addr.port())
+        };
+
+        let tcp_map = TcpMap(sorted_rules(&fwds, &defaults).unwrap(), None, defaults);
+        assert_eq!(Some(3u16), upstream_port(&tcp_map, "8.8.8.8:53"));
+        assert_eq!(Some(4u16), upstream_port(&tcp_map, "8.8.8.8:80"));
+        assert_eq!(Some(2u16), upstream_port(&tcp_map, "8.8.4.4:53"));
+        assert_eq!(Some(1u16), upstream_port(&tcp_map, "1.1.1.1:53"));
+        assert_eq!(Some(5u16), upstream_port(&tcp_map, "1.1.1.1:1500"));
+        assert_eq!(Some(6u16), upstream_port(&tcp_map, "[2001:db8::53]:53"));
+        assert_eq!(Some(1u16), upstream_port(&tcp_map, "[2001:db9::53]:53"));
+        assert_eq!(None, upstream_port(&tcp_map, "1.1.1.1:123"));
+        assert_eq!(
+            Some(1024usize),
+            tcp_map
+                .lookup(&dst("8.8.8.8:53"))
+                .map(|r| r.settings.buffer_size)
+        );
+        assert_eq!(
+            Some(4096usize),
+            tcp_map
+                .lookup(&dst("8.8.8.8:80"))
+                .map(|r| r.settings.buffer_size)
+        );
+        assert_eq!(Some(Some(7u32)), tcp_map.lookup(&dst("8.8.8.8:80")).map(|r| r.settings.mark));
 
-        let configs = Configs {
-            port: outer_port,
-            udp: [Forwarders {
-                upstream_ip: ip,
-                upstream_port: inner_port,
-                orig_port: inner_port,
-            }]
-            .into(),
-            tcp: [Forwarders {
-                upstream_ip: ip,
-                upstream_port: inner_port,
-                orig_port: inner_port,
-            }]
-            .into(),
-        };
-
-        let runtime_configs = RuntimeConfigs::from(&configs);
-        assert_eq!(outer_port, runtime_configs.port);
-        assert_eq!(HashMap::from([(inner_port, (ip, inner_port))]), runtime_configs.tcp_map.0);
-        assert_eq!(HashMap::from([(inner_port, (ip, inner_port))]), runtime_configs.udp_map.0);
-    }
-
-    #[test]
-    fn test_ForwarderMap_get() {
-        let ip = Ipv4Addr::from([10u8, 0u8, 0u8, 1u8]);
-        let port = 53u16;
-        let no_port = 123u16;
-        let map = HashMap::from([(port, (ip, port))]);
-
-        let tcp_map = TcpMap(map.clone());
-        assert_eq!(Some(&(ip, port)), tcp_map.get(&port));
-        assert_eq!(None, tcp_map.get(&no_port));
-
-        let udp_map = UdpMap(map.clone());
-        assert_eq!(Some(&(ip, port)), udp_map.get(&port));
-        assert_eq!(None, udp_map.get(&no_port));
+        let udp_map = UdpMap(sorted_rules(&fwds, &defaults).unwrap(), Some(Target::Passthrough), defaults);
+        assert_eq!(Some(3u16), upstream_port(&udp_map, "8.8.8.8:53"));
+        assert_eq!(Some(5u16), upstream_port(&udp_map, "8.8.8.8:2000"));
+        assert_eq!(Some(&Target::Passthrough), udp_map.lookup(&dst("8.8.8.8:3000")).map(|r| r.target));
+        assert_eq!(Some(3000u16), upstream_port(&udp_map, "8.8.8.8:3000"));
+        assert_eq!(Some(defaults), udp_map.lookup(&dst("8.8.8.8:3000")).map(|r| r.settings));
     }
 }
@@ -0,0 +1,512 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use arc_swap::ArcSwap;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+/// Load balancing strategy of an upstream pool
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Strategy {
+    /// Each upstream in turn, ignoring weights
+    #[default]
+    RoundRobin,
+    /// Each upstream in turn, as many times as its weight
+    Weighted,
+    /// The upstream with the fewest active connections relative to its weight
+    LeastConnections,
+    /// The same upstream for the same client IP, spread by weight
+    SourceHash,
+}
+
+/// Member of an upstream pool
+#[derive(Debug)]
+pub(crate) struct Upstream {
+    pub(crate) addr: SocketAddr,
+    pub(crate) weight: u32,
+    active: AtomicUsize,
+    up: AtomicBool,
+}
+
+impl Upstream {
+    /// Connections or sessions currently using this upstream
+    pub(crate) fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Whether the upstream passes its health checks, always true without health checking
+    pub(crate) fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_up(&self, up: bool) {
+        self.up.store(up, Ordering::Relaxed);
+    }
+}
+
+/// Health check of the members of an upstream pool
+///
+/// * TCP upstreams are checked by connecting to them
+/// * UDP upstreams are sent `payload` and, if `expect` is set, must reply with a datagram containing it
+/// * A member goes down after `fall` consecutive failures and up again after `rise` consecutive successes
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct HealthCheck {
+    pub(crate) interval: Duration,
+    pub(crate) timeout: Duration,
+    pub(crate) rise: u32,
+    pub(crate) fall: u32,
+    pub(crate) payload: Vec<u8>,
+    pub(crate) expect: Option<Vec<u8>>,
+}
+
+/// An upstream picked for a connection or session, counted as active until dropped
+pub(crate) struct Lease(Arc<Upstream>);
+
+impl Lease {
+    fn new(upstream: Arc<Upstream>) -> Self {
+        upstream.active.fetch_add(1usize, Ordering::Relaxed);
+        Self(upstream)
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.0.addr
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1usize, Ordering::Relaxed);
+    }
+}
+
+/// Connect retry of a TCP upstream pool
+///
+/// * A failed connection is retried on another healthy member not tried yet, else on the same one after `backoff`
+/// * `backoff` doubles on each retry of the same member
+/// * All `attempts` must complete within `deadline`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Retry {
+    pub(crate) attempts: u32,
+    pub(crate) backoff: Duration,
+    pub(crate) deadline: Duration,
+}
+
+/// Upstream host - an IP or a hostname resolved in the background
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(try_from = "String")]
+pub(crate) enum Host {
+    Ip(IpAddr),
+    Name(String),
+}
+
+impl FromStr for Host {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(ip) = IpAddr::from_str(s) {
+            return Ok(Host::Ip(ip));
+        }
+
+        let name = s.strip_suffix('.').unwrap_or(s);
+        let valid_label = |l: &str| {
+            (1usize..=63usize).contains(&l.len())
+                && !l.starts_with('-')
+                && !l.ends_with('-')
+                && l.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+
+        let numeric_tld = name
+            .rsplit('.')
+            .next()
+            .is_some_and(|l| l.chars().all(|c| c.is_ascii_digit()));
+
+        match name.len() <= 253usize && !numeric_tld && name.split('.').all(valid_label) {
+            true => Ok(Host::Name(name.to_ascii_lowercase())),
+            false => Err(format!("Invalid upstream host \"{s}\"")),
+        }
+    }
+}
+
+impl TryFrom<String> for Host {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::from_str(&s)
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::Ip(ip) => write!(f, "{ip}"),
+            Host::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Configured member of an upstream pool
+///
+/// * A hostname member contributes every address it resolves to, each with the member weight
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct Member {
+    pub(crate) host: Host,
+    pub(crate) port: u16,
+    pub(crate) weight: u32,
+}
+
+/// Upstreams of a rule & the strategy to pick one of them
+#[derive(Debug)]
+pub(crate) struct UpstreamPool {
+    strategy: Strategy,
+    members: Vec<Member>,
+    /// Last addresses of the hostname members
+    resolved: Mutex<HashMap<String, Vec<IpAddr>>>,
+    upstreams: ArcSwap<Vec<Arc<Upstream>>>,
+    health_check: Option<HealthCheck>,
+    retry: Option<Retry>,
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub(crate) fn new(strategy: Strategy, members: &[Member], health_check: Option<HealthCheck>, retry: Option<Retry>) -> Result<Self, String> {
+        if members.is_empty() {
+            return Err("Upstream pool needs at least one upstream".into());
+        }
+
+        if let Some(m) = members.iter().find(|m| m.weight == 0u32) {
+            return Err(format!("Weight of upstream {}:{} must be positive", m.host, m.port));
+        }
+
+        let pool = Self {
+            strategy,
+            members: members.to_vec(),
+            resolved: Mutex::new(HashMap::new()),
+            upstreams: ArcSwap::from_pointee(Vec::new()),
+            health_check,
+            retry,
+            next: AtomicUsize::new(0usize),
+        };
+        pool.rebuild(&HashMap::new());
+
+        Ok(pool)
+    }
+
+    /// Current upstreams, hostname members being expanded to their last resolved addresses
+    pub(crate) fn upstreams(&self) -> Arc<Vec<Arc<Upstream>>> {
+        self.upstreams.load_full()
+    }
+
+    /// Hostnames of the members to resolve
+    pub(crate) fn hostnames(&self) -> impl Iterator<Item = &str> {
+        self.members.iter().filter_map(|m| match &m.host {
+            Host::Name(name) => Some(name.as_str()),
+            Host::Ip(_) => None,
+        })
+    }
+
+    /// Last addresses of a hostname member, `None` until it's resolved
+    pub(crate) fn resolved(&self, name: &str) -> Option<Vec<IpAddr>> {
+        self.resolved
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(name)
+            .cloned()
+    }
+
+    /// Updates the addresses of a hostname member, keeping the state of the upstreams already known
+    pub(crate) fn set_resolved(&self, name: &str, ips: &[IpAddr]) {
+        if !self.hostnames().any(|n| n == name) {
+            return;
+        }
+
+        let mut resolved = self.resolved.lock().unwrap_or_else(|e| e.into_inner());
+        resolved.insert(name.to_string(), ips.to_vec());
+        self.rebuild(&resolved);
+    }
+
+    fn rebuild(&self, resolved: &HashMap<String, Vec<IpAddr>>) {
+        let current = self.upstreams.load();
+        let mut upstreams: Vec<Arc<Upstream>> = Vec::new();
+
+        for m in &self.members {
+            let ips = match &m.host {
+                Host::Ip(ip) => std::slice::from_ref(ip),
+                Host::Name(name) => resolved
+                    .get(name)
+                    .map(|ips| ips.as_slice())
+                    .unwrap_or_default(),
+            };
+
+            for addr in ips.iter().map(|ip| SocketAddr::new(*ip, m.port)) {
+                if upstreams.iter().any(|u| u.addr == addr) {
+                    continue;
+                }
+
+                let known = current
+                    .iter()
+                    .find(|u| u.addr == addr && u.weight == m.weight);
+                upstreams.push(match known {
+                    Some(u) => u.clone(),
+                    None => Arc::new(Upstream {
+                        addr,
+                        weight: m.weight,
+                        active: AtomicUsize::new(0usize),
+                        up: AtomicBool::new(true),
+                    }),
+                });
+            }
+        }
+
+        self.upstreams.store(Arc::new(upstreams));
+    }
+
+    pub(crate) fn health_check(&self) -> Option<&HealthCheck> {
+        self.health_check.as_ref()
+    }
+
+    pub(crate) fn retry(&self) -> Option<&Retry> {
+        self.retry.as_ref()
+    }
+
+    /// Picks a healthy upstream for a client as per the pool strategy, if any
+    pub(crate) fn pick(&self, src: &SocketAddr) -> Option<Lease> {
+        let upstreams = self.upstreams.load();
+
+        let upstream = match self.strategy {
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1usize, Ordering::Relaxed);
+                let len = upstreams.len();
+
+                (0..len)
+                    .map(|i| &upstreams[(start + i) % len])
+                    .find(|u| u.is_up())
+            },
+            Strategy::Weighted => Self::by_weight(&upstreams, self.next.fetch_add(1usize, Ordering::Relaxed) as u64),
+            Strategy::LeastConnections => upstreams
+                .iter()
+                .filter(|u| u.is_up())
+                .min_by(|a, b| (a.active() as u64 * b.weight as u64).cmp(&(b.active() as u64 * a.weight as u64))),
+            Strategy::SourceHash => {
+                let mut hasher = DefaultHasher::new();
+                src.ip().hash(&mut hasher);
+                Self::by_weight(&upstreams, hasher.finish())
+            },
+        };
+
+        upstream.map(|u| Lease::new(u.clone()))
+    }
+
+    /// Picks a healthy upstream for a client among those not tried yet, if any
+    pub(crate) fn pick_untried(&self, src: &SocketAddr, tried: &[SocketAddr]) -> Option<Lease> {
+        match self.pick(src) {
+            Some(lease) if !tried.contains(&lease.addr()) => Some(lease),
+            _ => self
+                .upstreams
+                .load()
+                .iter()
+                .find(|u| u.is_up() && !tried.contains(&u.addr))
+                .map(|u| Lease::new(u.clone())),
+        }
+    }
+
+    /// Healthy upstream owning the `n`th slot when every healthy upstream gets as many slots as its weight
+    fn by_weight(upstreams: &[Arc<Upstream>], n: u64) -> Option<&Arc<Upstream>> {
+        let total_weight = upstreams
+            .iter()
+            .filter(|u| u.is_up())
+            .map(|u| u.weight as u64)
+            .sum::<u64>();
+        let mut slot = n.checked_rem(total_weight)?;
+
+        for upstream in upstreams.iter().filter(|u| u.is_up()) {
+            match slot.checked_sub(upstream.weight as u64) {
+                Some(rest) => slot = rest,
+                None => return Some(upstream),
+            }
+        }
+
+        None
+    }
+}
+
+impl PartialEq for UpstreamPool {
+    fn eq(&self, other: &Self) -> bool {
+        self.strategy == other.strategy && self.members == other.members && self.health_check == other.health_check && self.retry == other.retry
+    }
+}
+
+impl Eq for UpstreamPool {}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10u8, 0u8, 0u8, 1u8], port))
+    }
+
+    fn member(addr: SocketAddr, weight: u32) -> Member {
+        Member {
+            host: Host::Ip(addr.ip()),
+            port: addr.port(),
+            weight,
+        }
+    }
+
+    fn picks(pool: &UpstreamPool, src: &SocketAddr, n: usize) -> HashMap<u16, usize> {
+        let mut counts = HashMap::new();
+        for _ in 0..n {
+            *counts
+                .entry(pool.pick(src).unwrap().addr().port())
+                .or_insert(0usize) += 1usize;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_Host_from_str() {
+        assert_eq!(Host::Ip(IpAddr::from([10u8, 0u8, 0u8, 1u8])), Host::from_str("10.0.0.1").unwrap());
+        assert_eq!(Host::Ip(IpAddr::from_str("fd00::1").unwrap()), Host::from_str("fd00::1").unwrap());
+        assert_eq!(Host::Name("dns.example.com".into()), Host::from_str("DNS.example.com.").unwrap());
+        assert_eq!(Host::Name("localhost".into()), Host::from_str("localhost").unwrap());
+
+        assert!(Host::from_str("").is_err());
+        assert!(Host::from_str("10.0.0").is_err());
+        assert!(Host::from_str("-bad.example.com").is_err());
+        assert!(Host::from_str("bad..example.com").is_err());
+        assert!(Host::from_str("bad host").is_err());
+    }
+
+    #[test]
+    fn test_UpstreamPool_set_resolved() {
+        let src = SocketAddr::from_str("192.168.1.10:40000").unwrap();
+        let named = Member {
+            host: Host::Name("backend.lan".into()),
+            port: 53u16,
+            weight: 2u32,
+        };
+        let pool = UpstreamPool::new(Strategy::RoundRobin, &[member(addr(1u16), 1u32), named.clone()], None, None).unwrap();
+        assert_eq!(vec!["backend.lan"], pool.hostnames().collect::<Vec<_>>());
+        assert_eq!(1usize, pool.upstreams().len());
+
+        let (ip1, ip2) = (IpAddr::from([10u8, 0u8, 0u8, 2u8]), IpAddr::from([10u8, 0u8, 0u8, 3u8]));
+        pool.set_resolved("backend.lan", &[ip1, ip2]);
+        let upstreams = pool.upstreams();
+        assert_eq!(
+            vec![addr(1u16), SocketAddr::new(ip1, 53u16), SocketAddr::new(ip2, 53u16)],
+            upstreams.iter().map(|u| u.addr).collect::<Vec<_>>()
+        );
+        assert_eq!(2u32, upstreams[2].weight);
+
+        // state of the upstreams still resolved is kept
+        upstreams[2].set_up(false);
+        let lease = pool.pick(&src).unwrap();
+        pool.set_resolved("backend.lan", &[ip2]);
+        assert_eq!(2usize, pool.upstreams().len());
+        assert!(!pool.upstreams()[1].is_up());
+        assert!(Arc::ptr_eq(&lease.0, &pool.upstreams()[0]));
+
+        pool.set_resolved("other.lan", &[ip1]);
+        assert_eq!(2usize, pool.upstreams().len());
+
+        let same = UpstreamPool::new(Strategy::RoundRobin, &[member(addr(1u16), 1u32), named], None, None).unwrap();
+        assert!(pool == same);
+    }
+
+    #[test]
+    fn test_UpstreamPool_new() {
+        assert!(UpstreamPool::new(Strategy::RoundRobin, &[], None, None).is_err());
+        assert!(UpstreamPool::new(Strategy::Weighted, &[member(addr(1u16), 1u32), member(addr(2u16), 0u32)], None, None).is_err());
+        assert!(UpstreamPool::new(Strategy::Weighted, &[member(addr(1u16), 1u32), member(addr(2u16), 2u32)], None, None).is_ok());
+    }
+
+    #[test]
+    fn test_UpstreamPool_pick() {
+        let src = SocketAddr::from_str("192.168.1.10:40000").unwrap();
+        let members = [member(addr(1u16), 1u32), member(addr(2u16), 3u32)];
+
+        let round_robin = UpstreamPool::new(Strategy::RoundRobin, &members, None, None).unwrap();
+        assert_eq!(HashMap::from([(1u16, 4usize), (2u16, 4usize)]), picks(&round_robin, &src, 8usize));
+
+        let weighted = UpstreamPool::new(Strategy::Weighted, &members, None, None).unwrap();
+        assert_eq!(HashMap::from([(1u16, 2usize), (2u16, 6usize)]), picks(&weighted, &src, 8usize));
+
+        let source_hash = UpstreamPool::new(Strategy::SourceHash, &members, None, None).unwrap();
+        assert_eq!(1usize, picks(&source_hash, &src, 8usize).len());
+        let other_port = SocketAddr::new(src.ip(), 40001u16);
+        assert_eq!(source_hash.pick(&src).unwrap().addr(), source_hash.pick(&other_port).unwrap().addr());
+
+        let least_conns = UpstreamPool::new(Strategy::LeastConnections, &members, None, None).unwrap();
+        let leases = (0..4)
+            .map(|_| least_conns.pick(&src).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(1usize, least_conns.upstreams()[0].active());
+        assert_eq!(3usize, least_conns.upstreams()[1].active());
+
+        drop(leases);
+        assert_eq!(0usize, least_conns.upstreams()[0].active());
+        assert_eq!(0usize, least_conns.upstreams()[1].active());
+    }
+
+    #[test]
+    fn test_UpstreamPool_pick_untried() {
+        let src = SocketAddr::from_str("192.168.1.10:40000").unwrap();
+        let pool = UpstreamPool::new(
+            Strategy::SourceHash,
+            &[member(addr(1u16), 1u32), member(addr(2u16), 1u32), member(addr(3u16), 1u32)],
+            None,
+            None,
+        )
+        .unwrap();
+
+        let first = pool.pick(&src).unwrap().addr();
+        let mut tried = vec![first];
+        let second = pool.pick_untried(&src, &tried).unwrap().addr();
+        assert_ne!(first, second);
+
+        tried.push(second);
+        let third = pool.pick_untried(&src, &tried).unwrap().addr();
+        assert!(!tried.contains(&third));
+
+        pool.upstreams()
+            .iter()
+            .filter(|u| u.addr == third)
+            .for_each(|u| u.set_up(false));
+        assert!(pool.pick_untried(&src, &tried).is_none());
+    }
+
+    #[test]
+    fn test_UpstreamPool_pick_healthy() {
+        let src = SocketAddr::from_str("192.168.1.10:40000").unwrap();
+        let members = [member(addr(1u16), 1u32), member(addr(2u16), 3u32), member(addr(3u16), 1u32)];
+
+        for strategy in [Strategy::RoundRobin, Strategy::Weighted, Strategy::LeastConnections, Strategy::SourceHash] {
+            let pool = UpstreamPool::new(strategy, &members, None, None).unwrap();
+
+            pool.upstreams()[1].set_up(false);
+            let counts = picks(&pool, &src, 10usize);
+            assert!(!counts.contains_key(&2u16));
+            assert_eq!(10usize, counts.values().sum::<usize>());
+
+            pool.upstreams().iter().for_each(|u| u.set_up(false));
+            assert!(pool.pick(&src).is_none());
+
+            pool.upstreams()[2].set_up(true);
+            assert_eq!(addr(3u16), pool.pick(&src).unwrap().addr());
+        }
+    }
+}
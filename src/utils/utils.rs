@@ -10,13 +10,15 @@ use log4rs::{
     filter::threshold::ThresholdFilter,
     init_config,
 };
+use serde::Deserialize;
 use serde_json::from_str;
 use std::{
     io::{Error, ErrorKind, Result as IoResult},
+    net::SocketAddr,
     os::unix::fs::PermissionsExt,
     path::PathBuf,
 };
-use tokio::fs::read_to_string;
+use tokio::{fs::read_to_string, process::Command};
 
 use super::{
     cap_bindings::{__user_cap_data_struct, cap_to_index, cap_to_mask},
@@ -52,6 +54,87 @@ pub(crate) async fn read_config(path: &PathBuf) -> IoResult<Configs> {
     from_str(&read_to_string(path).await?).map_err(|e| Error::new(ErrorKind::InvalidData, format!("Failed to deserialize configuration file - {e}")))
 }
 
+/// Policy routing rule as listed by `ip -j rule show`
+#[derive(Deserialize)]
+struct IpRule {
+    fwmark: Option<String>,
+    table: Option<String>,
+}
+
+/// Route as listed by `ip -j route show`
+#[derive(Deserialize)]
+struct IpRoute {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    dst: String,
+}
+
+/// Tables looked up by the rules matching a firewall mark
+fn fwmark_tables(rules: &str) -> Result<Vec<String>, String> {
+    let rules: Vec<IpRule> = from_str(rules).map_err(|e| format!("Failed to parse routing rules - {e}"))?;
+
+    Ok(rules
+        .into_iter()
+        .filter(|r| r.fwmark.is_some())
+        .filter_map(|r| r.table)
+        .collect())
+}
+
+/// Whether the routes hold a `local` default one, delivering every address to the host itself
+fn has_local_default(routes: &str) -> Result<bool, String> {
+    let routes: Vec<IpRoute> = from_str(routes).map_err(|e| format!("Failed to parse routes - {e}"))?;
+
+    Ok(routes
+        .iter()
+        .any(|r| r.kind.as_deref() == Some("local") && r.dst == "default"))
+}
+
+/// Runs `ip -j` for an IP family, without blocking the runtime
+async fn ip(family: &str, args: &[&str]) -> Result<String, String> {
+    let out = Command::new("ip")
+        .arg(family)
+        .arg("-j")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run ip - {e}"))?;
+
+    match out.status.success() {
+        true => Ok(String::from_utf8_lossy(&out.stdout).into_owned()),
+        false => Err(format!("ip {} failed - {}", args.join(" "), String::from_utf8_lossy(&out.stderr).trim())),
+    }
+}
+
+/// Checks the return routing needed by rules spoofing the client source, for the IP families of their listen addresses
+///
+/// * Passes if any `ip rule` with a `fwmark` selector looks up a table holding a `local` route for `default`, as set up for TPROXY
+/// * The mark, mask & priority of that rule aren't compared with anything, so a rule meant for other traffic passes too
+/// * Whether the firewall marks the upstream replies (e.g. `-m socket --transparent`) can't be seen, nor rules selecting replies without a fwmark (e.g. `iif`) or `local` routes narrower than `default`
+pub(crate) async fn check_return_routing(addrs: &[SocketAddr]) -> Result<(), String> {
+    for (family, v6) in [("-4", false), ("-6", true)] {
+        if !addrs.iter().any(|a| a.is_ipv6() == v6) {
+            continue;
+        }
+
+        let mut routed = false;
+        for table in fwmark_tables(&ip(family, &["rule", "show"]).await?)? {
+            if has_local_default(&ip(family, &["route", "show", "table", &table]).await?)? {
+                routed = true;
+                break;
+            }
+        }
+
+        if !routed {
+            return Err(format!(
+                "No fwmark rule delivers {} upstream replies locally, e.g. `ip {family} rule add fwmark 1 lookup 100` & `ip {family} route add local default dev lo table 100`",
+                if v6 { "IPv6" } else { "IPv4" }
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 /// Enable logging based on provided optional log directory. If provided it logs to file, else falls back to console logging
 pub(crate) fn enable_logging(log_dir: Option<&PathBuf>) -> Result<Handle, LogError> {
     let config = match log_dir {
@@ -132,6 +215,22 @@ mod tests {
 
     use super::*;
 
+    #[tokio::test]
+    async fn test_check_return_routing() {
+        let rules = r#"[{"priority":0,"src":"all","table":"local"},{"priority":100,"src":"all","fwmark":"0x1","table":"100"},{"priority":32766,"src":"all","table":"main"}]"#;
+        assert_eq!(vec!["100".to_string()], fwmark_tables(rules).unwrap());
+        assert!(fwmark_tables("[]").unwrap().is_empty());
+        assert!(fwmark_tables("abcd").is_err());
+
+        let routes = r#"[{"type":"local","dst":"default","dev":"lo","scope":"host","flags":[]}]"#;
+        assert!(has_local_default(routes).unwrap());
+        let routes = r#"[{"dst":"default","gateway":"192.0.2.1","dev":"eth0","flags":[]},{"type":"local","dst":"127.0.0.1","dev":"lo","flags":[]}]"#;
+        assert!(!has_local_default(routes).unwrap());
+
+        // nothing spoofed, nothing to check
+        assert!(check_return_routing(&[]).await.is_ok());
+    }
+
     #[tokio::test]
     async fn test_read_config() {
         let dir = tempdir().unwrap();